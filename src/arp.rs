@@ -1,11 +1,17 @@
 use std::collections::HashMap;
+use std::fmt;
+use std::net::Ipv4Addr;
 use std::sync::RwLock;
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::time::{Duration, Instant};
 
 use anyhow::Result;
+use log::{debug, error, info};
 
-use crate::ethernet::{ETHERNET_ADDRESS_LENGTH, ETHERNET_TYPE_IP};
-use crate::ip::IP_ADDRESS_LENGTH;
+use crate::ethernet::{
+    address_to_string, ETHERNET_ADDRESS_BROADCAST, ETHERNET_ADDRESS_LENGTH, ETHERNET_TYPE_IP,
+};
+use crate::ip::{IPPacket, IP_ADDRESS_LENGTH};
+use crate::net::{NetDeviceContext, NET_PROTOCOL_ARP, NET_PROTOCOL_IP};
 
 const ARP_HARDWARE_TYPE_ETHERNET: u16 = 0x0001;
 const ARP_PROTOCOL_TYPE_IP: u16 = ETHERNET_TYPE_IP;
@@ -34,50 +40,272 @@ const ETHERNET_HARDWARE_LENGTH_USIZE: usize = ETHERNET_ADDRESS_LENGTH as usize;
 const IP_PROTOCOL_LENGTH_USIZE: usize = IP_ADDRESS_LENGTH as usize;
 type ARPEthernetIPPacket = ARPPacket<ETHERNET_HARDWARE_LENGTH_USIZE, IP_PROTOCOL_LENGTH_USIZE>;
 
-const ARP_CACHE_TIMEOUT_SECONDS: u64 = 30;
+const ARP_HEADER_LENGTH: usize = 8;
+
+impl ARPHeader {
+    fn parse(data: &[u8]) -> Result<Self> {
+        if data.len() < ARP_HEADER_LENGTH {
+            return Err(anyhow::anyhow!("too short header, len={}", data.len()));
+        }
+        Ok(ARPHeader {
+            hardware_type: u16::from_be_bytes([data[0], data[1]]),
+            protocol_type: u16::from_be_bytes([data[2], data[3]]),
+            hardware_length: data[4],
+            protocol_length: data[5],
+            opcode: u16::from_be_bytes([data[6], data[7]]),
+        })
+    }
+    fn serialize(&self) -> Vec<u8> {
+        let mut data = Vec::with_capacity(ARP_HEADER_LENGTH);
+        data.extend_from_slice(&self.hardware_type.to_be_bytes());
+        data.extend_from_slice(&self.protocol_type.to_be_bytes());
+        data.push(self.hardware_length);
+        data.push(self.protocol_length);
+        data.extend_from_slice(&self.opcode.to_be_bytes());
+        data
+    }
+}
+
+impl<const T: usize, const U: usize> ARPPacket<T, U> {
+    const LENGTH: usize = ARP_HEADER_LENGTH + 2 * (T + U);
+
+    fn new(
+        hardware_type: u16,
+        protocol_type: u16,
+        opcode: u16,
+        sender_hardware_address: [u8; T],
+        sender_protocol_address: [u8; U],
+        target_hardware_address: [u8; T],
+        target_protocol_address: [u8; U],
+    ) -> Self {
+        ARPPacket {
+            header: ARPHeader {
+                hardware_type,
+                protocol_type,
+                hardware_length: T as u8,
+                protocol_length: U as u8,
+                opcode,
+            },
+            sender_hardware_address,
+            sender_protocol_address,
+            target_hardware_address,
+            target_protocol_address,
+        }
+    }
+
+    /// Parses a packet whose address sizes are `T`/`U` and whose types match
+    /// `hardware_type`/`protocol_type`. Trailing bytes (e.g. Ethernet padding) are ignored.
+    fn parse(data: &[u8], hardware_type: u16, protocol_type: u16) -> Result<Self> {
+        let header = ARPHeader::parse(data)?;
+        if header.hardware_type != hardware_type {
+            return Err(anyhow::anyhow!(
+                "unsupported hardware type, type=0x{:04x}",
+                header.hardware_type
+            ));
+        }
+        if header.protocol_type != protocol_type {
+            return Err(anyhow::anyhow!(
+                "unsupported protocol type, type=0x{:04x}",
+                header.protocol_type
+            ));
+        }
+        if header.hardware_length as usize != T {
+            return Err(anyhow::anyhow!(
+                "invalid hardware length, len={}",
+                header.hardware_length
+            ));
+        }
+        if header.protocol_length as usize != U {
+            return Err(anyhow::anyhow!(
+                "invalid protocol length, len={}",
+                header.protocol_length
+            ));
+        }
+        if header.opcode != ARP_OPCODE_REQUEST && header.opcode != ARP_OPCODE_REPLY {
+            return Err(anyhow::anyhow!("invalid opcode, opcode={}", header.opcode));
+        }
+        if data.len() < Self::LENGTH {
+            return Err(anyhow::anyhow!("too short packet, len={}", data.len()));
+        }
+        let mut offset = ARP_HEADER_LENGTH;
+        let mut sender_hardware_address = [0; T];
+        sender_hardware_address.copy_from_slice(&data[offset..offset + T]);
+        offset += T;
+        let mut sender_protocol_address = [0; U];
+        sender_protocol_address.copy_from_slice(&data[offset..offset + U]);
+        offset += U;
+        let mut target_hardware_address = [0; T];
+        target_hardware_address.copy_from_slice(&data[offset..offset + T]);
+        offset += T;
+        let mut target_protocol_address = [0; U];
+        target_protocol_address.copy_from_slice(&data[offset..offset + U]);
+        Ok(ARPPacket {
+            header,
+            sender_hardware_address,
+            sender_protocol_address,
+            target_hardware_address,
+            target_protocol_address,
+        })
+    }
+
+    fn serialize(&self) -> Vec<u8> {
+        let mut data = self.header.serialize();
+        data.reserve(Self::LENGTH - ARP_HEADER_LENGTH);
+        data.extend_from_slice(&self.sender_hardware_address);
+        data.extend_from_slice(&self.sender_protocol_address);
+        data.extend_from_slice(&self.target_hardware_address);
+        data.extend_from_slice(&self.target_protocol_address);
+        data
+    }
+}
+
+impl ARPEthernetIPPacket {
+    fn parse_ethernet_ip(data: &[u8]) -> Result<Self> {
+        Self::parse(data, ARP_HARDWARE_TYPE_ETHERNET, ARP_PROTOCOL_TYPE_IP)
+    }
+
+    fn request(
+        sender_hardware_address: [u8; ETHERNET_HARDWARE_LENGTH_USIZE],
+        sender_protocol_address: [u8; IP_PROTOCOL_LENGTH_USIZE],
+        target_protocol_address: [u8; IP_PROTOCOL_LENGTH_USIZE],
+    ) -> Self {
+        Self::new(
+            ARP_HARDWARE_TYPE_ETHERNET,
+            ARP_PROTOCOL_TYPE_IP,
+            ARP_OPCODE_REQUEST,
+            sender_hardware_address,
+            sender_protocol_address,
+            [0; ETHERNET_HARDWARE_LENGTH_USIZE],
+            target_protocol_address,
+        )
+    }
+
+    /// Builds the reply to `request` announcing `hardware_address` as the owner of
+    /// the requested protocol address.
+    fn reply(&self, hardware_address: [u8; ETHERNET_HARDWARE_LENGTH_USIZE]) -> Self {
+        Self::new(
+            ARP_HARDWARE_TYPE_ETHERNET,
+            ARP_PROTOCOL_TYPE_IP,
+            ARP_OPCODE_REPLY,
+            hardware_address,
+            self.target_protocol_address,
+            self.sender_hardware_address,
+            self.sender_protocol_address,
+        )
+    }
+}
+
+const ARP_CACHE_TIMEOUT: Duration = Duration::from_secs(30);
+/// How long a failed resolution is remembered before a new one may be started.
+const ARP_FAILED_TIMEOUT: Duration = Duration::from_secs(5);
+/// Requests sent for an address before giving up on it.
+const ARP_REQUEST_RETRY_MAX: u8 = 3;
+/// Time waited for a reply before sending the next request for an address.
+const ARP_REQUEST_RETRY_INTERVAL: Duration = Duration::from_secs(1);
+/// Bytes of packets held per address while its resolution is in progress, like
+/// `unres_qlen_bytes` of Linux. Room for the fragments of several datagrams of
+/// the maximum length.
+const ARP_PENDING_QUEUE_BYTES_MAX: usize = 212992;
+/// Short next to `ARP_REQUEST_RETRY_INTERVAL`, so that retries go out close to
+/// it after the previous request.
+pub(crate) const ARP_TIMER_INTERVAL: Duration = Duration::from_millis(100);
+pub(crate) const ARP_SWEEP_INTERVAL: Duration = Duration::from_secs(5);
+
 #[derive(Debug, PartialEq, Eq, Hash, Clone)]
-enum ARPCacheState {
+pub enum ARPCacheState {
     Free,
     Incomplete,
     Resolved,
     Static,
+    Failed,
 }
 #[derive(Debug, PartialEq, Eq, Hash, Clone)]
 struct ARPCacheEntry<const T: usize, const U: usize> {
+    device_index: u32,
     hardware_address: [u8; T],
     protocol_address: [u8; U],
     state: ARPCacheState,
-    timeout: u64,
+    /// When the entry expires, `None` for entries which never do.
+    timeout: Option<Instant>,
+    retry: u8,
+    /// When the last request was sent, for resolutions in progress.
+    requested: Option<Instant>,
+    pending: Vec<Vec<u8>>,
+}
+impl<const T: usize, const U: usize> ARPCacheEntry<T, U> {
+    /// Whether the hardware address can be used at `now`.
+    fn is_usable(&self, now: Instant) -> bool {
+        match self.state {
+            ARPCacheState::Static => true,
+            ARPCacheState::Resolved => self.timeout.is_some_and(|timeout| timeout > now),
+            ARPCacheState::Free | ARPCacheState::Incomplete | ARPCacheState::Failed => false,
+        }
+    }
 }
-type ARPEthernetIPCacheEntry =
-    ARPCacheEntry<ETHERNET_HARDWARE_LENGTH_USIZE, IP_PROTOCOL_LENGTH_USIZE>;
+
+/// Snapshot of a cache entry, as listed by `ARPContext::entries`.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct ARPCacheEntryInfo<const T: usize, const U: usize> {
+    pub device_index: u32,
+    pub hardware_address: [u8; T],
+    pub protocol_address: [u8; U],
+    pub state: ARPCacheState,
+    /// Time left before the entry expires, `None` for entries which never do.
+    pub lifetime: Option<Duration>,
+    pub pending: usize,
+}
+pub type ARPEthernetIPCacheEntryInfo =
+    ARPCacheEntryInfo<ETHERNET_HARDWARE_LENGTH_USIZE, IP_PROTOCOL_LENGTH_USIZE>;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum ARPError {
+    /// Too many bytes are already waiting for the address to be resolved.
+    QueueFull,
+    /// Nobody answered the requests for the address.
+    Unreachable,
+}
+impl fmt::Display for ARPError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ARPError::QueueFull => write!(f, "arp pending queue full"),
+            ARPError::Unreachable => write!(f, "arp resolution failed"),
+        }
+    }
+}
+impl std::error::Error for ARPError {}
 
 #[derive(Debug)]
-struct ARPContext<const T: usize, const U: usize> {
+pub struct ARPContext<const T: usize, const U: usize> {
     cache: RwLock<HashMap<[u8; U], ARPCacheEntry<T, U>>>,
 }
-type ARPEthernetIPContext = ARPContext<ETHERNET_HARDWARE_LENGTH_USIZE, IP_PROTOCOL_LENGTH_USIZE>;
+pub type ARPEthernetIPContext =
+    ARPContext<ETHERNET_HARDWARE_LENGTH_USIZE, IP_PROTOCOL_LENGTH_USIZE>;
 impl<const T: usize, const U: usize> ARPContext<T, U> {
-    fn new() -> Self {
+    pub(crate) fn new() -> Self {
         ARPContext {
             cache: RwLock::new(HashMap::new()),
         }
     }
 
-    fn lookup(&self, protocol_address: [u8; U]) -> Result<Option<[u8; T]>> {
+    pub fn lookup(&self, protocol_address: [u8; U]) -> Result<Option<[u8; T]>> {
         let cache = self
             .cache
             .read()
             .map_err(|_| anyhow::anyhow!("Failed to read lock"))?;
         if let Some(entry) = cache.get(&protocol_address) {
-            if entry.state != ARPCacheState::Free {
+            if entry.is_usable(Instant::now()) {
                 return Ok(Some(entry.hardware_address));
             }
         }
         Ok(None)
     }
 
-    fn insert(&self, hardware_address: [u8; T], protocol_address: [u8; U]) -> Result<()> {
+    fn insert(
+        &self,
+        device_index: u32,
+        hardware_address: [u8; T],
+        protocol_address: [u8; U],
+    ) -> Result<()> {
         let mut cache = self
             .cache
             .write()
@@ -85,31 +313,132 @@ impl<const T: usize, const U: usize> ARPContext<T, U> {
         cache.insert(
             protocol_address,
             ARPCacheEntry {
+                device_index,
                 hardware_address,
                 protocol_address,
                 state: ARPCacheState::Resolved,
-                timeout: SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs()
-                    + ARP_CACHE_TIMEOUT_SECONDS,
+                timeout: Some(Instant::now() + ARP_CACHE_TIMEOUT),
+                retry: 0,
+                requested: None,
+                pending: Vec::new(),
             },
         );
         Ok(())
     }
 
-    fn update(&self, hardware_address: [u8; T], protocol_address: [u8; U]) -> Result<()> {
+    /// Refreshes the entry of `protocol_address` if there is one. Returns `None` when
+    /// there was no entry, otherwise the packets that were waiting for the resolution.
+    fn update(
+        &self,
+        hardware_address: [u8; T],
+        protocol_address: [u8; U],
+    ) -> Result<Option<(u32, Vec<Vec<u8>>)>> {
         let mut cache = self
             .cache
             .write()
             .map_err(|_| anyhow::anyhow!("Failed to write lock"))?;
         if let Some(entry) = cache.get_mut(&protocol_address) {
-            entry.hardware_address = hardware_address;
-            entry.state = ARPCacheState::Resolved;
-            entry.timeout =
-                SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() + ARP_CACHE_TIMEOUT_SECONDS;
+            if entry.state == ARPCacheState::Free {
+                return Ok(None);
+            }
+            if entry.state != ARPCacheState::Static {
+                entry.hardware_address = hardware_address;
+                entry.state = ARPCacheState::Resolved;
+                entry.timeout = Some(Instant::now() + ARP_CACHE_TIMEOUT);
+                entry.retry = 0;
+                entry.requested = None;
+            }
+            return Ok(Some((
+                entry.device_index,
+                std::mem::take(&mut entry.pending),
+            )));
+        }
+        Ok(None)
+    }
+
+    /// Pins `protocol_address` to `hardware_address` on `device_index`, like `arp -s`.
+    /// Static entries never expire and are not overwritten by received packets.
+    pub fn add_static(
+        &self,
+        device_index: u32,
+        hardware_address: [u8; T],
+        protocol_address: [u8; U],
+    ) -> Result<()> {
+        let mut cache = self
+            .cache
+            .write()
+            .map_err(|_| anyhow::anyhow!("Failed to write lock"))?;
+        let previous = cache.insert(
+            protocol_address,
+            ARPCacheEntry {
+                device_index,
+                hardware_address,
+                protocol_address,
+                state: ARPCacheState::Static,
+                timeout: None,
+                retry: 0,
+                requested: None,
+                pending: Vec::new(),
+            },
+        );
+        if let Some(previous) = previous {
+            if !previous.pending.is_empty() {
+                info!(
+                    "replaced by static entry, dropped {} packets, dev={}",
+                    previous.pending.len(),
+                    previous.device_index
+                );
+            }
         }
         Ok(())
     }
 
-    fn delete(&self, protocol_address: [u8; U]) -> Result<()> {
+    /// Lists the entries in use with their state and remaining lifetime, like `arp -a`.
+    pub fn entries(&self) -> Result<Vec<ARPCacheEntryInfo<T, U>>> {
+        let cache = self
+            .cache
+            .read()
+            .map_err(|_| anyhow::anyhow!("Failed to read lock"))?;
+        let now = Instant::now();
+        let mut entries = cache
+            .values()
+            .filter(|entry| entry.state != ARPCacheState::Free)
+            .map(|entry| ARPCacheEntryInfo {
+                device_index: entry.device_index,
+                hardware_address: entry.hardware_address,
+                protocol_address: entry.protocol_address,
+                state: entry.state.clone(),
+                lifetime: entry
+                    .timeout
+                    .map(|timeout| timeout.saturating_duration_since(now)),
+                pending: entry.pending.len(),
+            })
+            .collect::<Vec<_>>();
+        entries.sort_by_key(|entry| (entry.device_index, entry.protocol_address));
+        Ok(entries)
+    }
+
+    /// Frees all the dynamic entries learned on `device_index`, like `ip neigh flush dev`.
+    /// Returns the number of entries flushed.
+    pub fn flush(&self, device_index: u32) -> Result<usize> {
+        let mut cache = self
+            .cache
+            .write()
+            .map_err(|_| anyhow::anyhow!("Failed to write lock"))?;
+        let before = cache.len();
+        cache.retain(|_, entry| {
+            entry.device_index != device_index || entry.state == ARPCacheState::Static
+        });
+        info!(
+            "flushed {} entries, dev={}",
+            before - cache.len(),
+            device_index
+        );
+        Ok(before - cache.len())
+    }
+
+    /// Frees the entry of `protocol_address` whatever its state, like `arp -d`.
+    pub fn delete(&self, protocol_address: [u8; U]) -> Result<()> {
         let mut cache = self
             .cache
             .write()
@@ -117,8 +446,560 @@ impl<const T: usize, const U: usize> ARPContext<T, U> {
         if let Some(entry) = cache.get_mut(&protocol_address) {
             entry.hardware_address = [0; T];
             entry.state = ARPCacheState::Free;
-            entry.timeout = 0;
+            entry.timeout = None;
+            entry.retry = 0;
+            entry.requested = None;
+            entry.pending.clear();
+        }
+        Ok(())
+    }
+
+    /// Looks `protocol_address` up, starting a resolution on `device_index` when it is unknown.
+    ///
+    /// Returns `Ok(None)` while the resolution is in progress, in which case the
+    /// packets of `pending` are all taken into the queue, or none of them if they do
+    /// not fit, and sent once the reply arrives. The second value tells whether a new
+    /// request has to be broadcast by the caller.
+    fn resolve_or_queue(
+        &self,
+        device_index: u32,
+        protocol_address: [u8; U],
+        pending: &mut Vec<Vec<u8>>,
+    ) -> Result<(Option<[u8; T]>, bool)> {
+        let mut cache = self
+            .cache
+            .write()
+            .map_err(|_| anyhow::anyhow!("Failed to write lock"))?;
+        let now = Instant::now();
+        if let Some(entry) = cache.get_mut(&protocol_address) {
+            match entry.state {
+                _ if entry.is_usable(now) => {
+                    return Ok((Some(entry.hardware_address), false));
+                }
+                ARPCacheState::Incomplete => {
+                    let queued: usize = entry.pending.iter().map(Vec::len).sum();
+                    let added: usize = pending.iter().map(Vec::len).sum();
+                    if queued + added > ARP_PENDING_QUEUE_BYTES_MAX {
+                        return Err(ARPError::QueueFull.into());
+                    }
+                    entry.pending.append(pending);
+                    return Ok((None, false));
+                }
+                ARPCacheState::Failed if entry.timeout.is_some_and(|timeout| timeout > now) => {
+                    return Err(ARPError::Unreachable.into());
+                }
+                ARPCacheState::Resolved
+                | ARPCacheState::Static
+                | ARPCacheState::Failed
+                | ARPCacheState::Free => {}
+            }
+        }
+        cache.insert(
+            protocol_address,
+            ARPCacheEntry {
+                device_index,
+                hardware_address: [0; T],
+                protocol_address,
+                state: ARPCacheState::Incomplete,
+                timeout: None,
+                retry: 0,
+                requested: Some(now),
+                pending: std::mem::take(pending),
+            },
+        );
+        Ok((None, true))
+    }
+
+    /// Frees the entries whose lifetime is over. `Static` entries and resolutions
+    /// in progress are left alone.
+    pub(crate) fn sweep(&self) -> Result<()> {
+        let mut cache = self
+            .cache
+            .write()
+            .map_err(|_| anyhow::anyhow!("Failed to write lock"))?;
+        let now = Instant::now();
+        let before = cache.len();
+        cache.retain(|_, entry| match entry.state {
+            ARPCacheState::Static | ARPCacheState::Incomplete => true,
+            ARPCacheState::Resolved | ARPCacheState::Failed => {
+                entry.timeout.is_some_and(|timeout| timeout > now)
+            }
+            ARPCacheState::Free => false,
+        });
+        if cache.len() != before {
+            debug!("swept {} entries", before - cache.len());
+        }
+        Ok(())
+    }
+
+    /// Advances the resolutions whose last request went unanswered for
+    /// `ARP_REQUEST_RETRY_INTERVAL`.
+    fn retry(&self) -> Result<ARPRetries<U>> {
+        let mut cache = self
+            .cache
+            .write()
+            .map_err(|_| anyhow::anyhow!("Failed to write lock"))?;
+        let now = Instant::now();
+        let mut retries = Vec::new();
+        let mut failed = Vec::new();
+        for entry in cache.values_mut() {
+            if entry.state != ARPCacheState::Incomplete
+                || entry.requested.is_some_and(|requested| {
+                    now.duration_since(requested) < ARP_REQUEST_RETRY_INTERVAL
+                })
+            {
+                continue;
+            }
+            entry.retry += 1;
+            if entry.retry < ARP_REQUEST_RETRY_MAX {
+                entry.requested = Some(now);
+                retries.push((entry.device_index, entry.protocol_address));
+                continue;
+            }
+            entry.state = ARPCacheState::Failed;
+            entry.timeout = Some(now + ARP_FAILED_TIMEOUT);
+            entry.requested = None;
+            if !entry.pending.is_empty() {
+                info!(
+                    "resolution failed, dropped {} packets, dev={}",
+                    entry.pending.len(),
+                    entry.device_index
+                );
+                failed.append(&mut entry.pending);
+            }
+        }
+        Ok(ARPRetries { retries, failed })
+    }
+}
+
+/// What `ARPContext::retry` leaves to send.
+#[derive(Debug)]
+struct ARPRetries<const U: usize> {
+    /// Addresses, with their device, to send a new request for.
+    retries: Vec<(u32, [u8; U])>,
+    /// Packets of the resolutions given up.
+    failed: Vec<Vec<u8>>,
+}
+
+/// What `ARPEthernetIPContext::handle` leaves to send.
+#[derive(Debug)]
+struct ARPReception {
+    /// Reply to a request for an address of the interface.
+    reply: Option<ARPEthernetIPPacket>,
+    /// Device and packets of a resolution completed by the packet.
+    released: Option<(u32, Vec<Vec<u8>>)>,
+}
+
+impl ARPEthernetIPContext {
+    /// Runs the packet reception algorithm of RFC 826 for an interface owning
+    /// `ip_address`/`hardware_address`.
+    fn handle(
+        &self,
+        device_index: u32,
+        packet: &ARPEthernetIPPacket,
+        ip_address: [u8; IP_PROTOCOL_LENGTH_USIZE],
+        hardware_address: [u8; ETHERNET_HARDWARE_LENGTH_USIZE],
+    ) -> Result<ARPReception> {
+        let merged = self.update(
+            packet.sender_hardware_address,
+            packet.sender_protocol_address,
+        )?;
+        if packet.target_protocol_address != ip_address {
+            return Ok(ARPReception {
+                reply: None,
+                released: merged,
+            });
+        }
+        if merged.is_none() {
+            self.insert(
+                device_index,
+                packet.sender_hardware_address,
+                packet.sender_protocol_address,
+            )?;
+        }
+        let reply =
+            (packet.header.opcode == ARP_OPCODE_REQUEST).then(|| packet.reply(hardware_address));
+        Ok(ARPReception {
+            reply,
+            released: merged,
+        })
+    }
+
+    pub(crate) fn input(
+        &self,
+        context: &NetDeviceContext,
+        device_index: u32,
+        data: &[u8],
+    ) -> Result<()> {
+        let packet = ARPEthernetIPPacket::parse_ethernet_ip(data)?;
+        debug!(
+            "input, opcode={}, spa={}, sha={}, tpa={}",
+            packet.header.opcode,
+            Ipv4Addr::from(packet.sender_protocol_address),
+            address_to_string(&packet.sender_hardware_address),
+            Ipv4Addr::from(packet.target_protocol_address),
+        );
+        let interfaces = context.ip_interfaces(device_index)?;
+        let Some(interface) = interfaces
+            .iter()
+            .find(|interface| interface.unicast.to_be_bytes() == packet.target_protocol_address)
+            .or_else(|| interfaces.first())
+        else {
+            debug!("no ip interface, dev={}", device_index);
+            return Ok(());
+        };
+        let hardware_address = ethernet_address(context, device_index)?;
+        let ARPReception { reply, released } = self.handle(
+            device_index,
+            &packet,
+            interface.unicast.to_be_bytes(),
+            hardware_address,
+        )?;
+        if let Some(reply) = reply {
+            debug!(
+                "reply, tpa={}, tha={}",
+                Ipv4Addr::from(reply.target_protocol_address),
+                address_to_string(&reply.target_hardware_address),
+            );
+            context.transmit(
+                device_index,
+                NET_PROTOCOL_ARP,
+                reply.serialize(),
+                &reply.target_hardware_address,
+            )?;
+        }
+        if let Some((pending_device_index, pending)) = released {
+            if !pending.is_empty() {
+                debug!(
+                    "resolved, flush {} packets, pa={}",
+                    pending.len(),
+                    Ipv4Addr::from(packet.sender_protocol_address)
+                );
+            }
+            for data in pending {
+                context.transmit(
+                    pending_device_index,
+                    NET_PROTOCOL_IP,
+                    data,
+                    &packet.sender_hardware_address,
+                )?;
+            }
         }
         Ok(())
     }
+
+    /// Sends the IP packets of `data`, the fragments of one datagram, to
+    /// `protocol_address` on `device_index` without blocking.
+    ///
+    /// When the hardware address is unknown, an `Incomplete` entry is created, a request
+    /// is broadcast and the packets are held until the reply arrives. Once the retries
+    /// run out, the packets are dropped and reported with a Host Unreachable. Fails
+    /// with `ARPError::Unreachable` while a recent resolution of the address is
+    /// remembered as failed and with `ARPError::QueueFull` when the packets do not
+    /// fit in the queue.
+    pub(crate) fn output(
+        &self,
+        context: &NetDeviceContext,
+        device_index: u32,
+        protocol_address: [u8; IP_PROTOCOL_LENGTH_USIZE],
+        mut data: Vec<Vec<u8>>,
+    ) -> Result<()> {
+        let (hardware_address, request) =
+            self.resolve_or_queue(device_index, protocol_address, &mut data)?;
+        if request {
+            self.request(context, device_index, protocol_address)?;
+        }
+        if let Some(hardware_address) = hardware_address {
+            for data in data {
+                context.transmit(device_index, NET_PROTOCOL_IP, data, &hardware_address)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Sends the retries due and reports the packets of the resolutions given up
+    /// with a Host Unreachable.
+    ///
+    /// A failure is only logged, since the packets taken out of the cache would be
+    /// lost along with the rest of the work of the tick.
+    pub(crate) fn timer(&self, context: &NetDeviceContext) -> Result<()> {
+        let ARPRetries { retries, failed } = self.retry()?;
+        for (device_index, protocol_address) in retries {
+            if let Err(e) = self.request(context, device_index, protocol_address) {
+                error!(
+                    "retry failed, dev={}, tpa={}, err={}",
+                    device_index,
+                    Ipv4Addr::from(protocol_address),
+                    e
+                );
+            }
+        }
+        for data in failed {
+            let result = IPPacket::parse(&data)
+                .map_err(anyhow::Error::from)
+                .and_then(|packet| context.icmp_controller().host_unreachable(context, &packet));
+            if let Err(e) = result {
+                error!("unreachable packet not reported, err={}", e);
+            }
+        }
+        Ok(())
+    }
+
+    fn request(
+        &self,
+        context: &NetDeviceContext,
+        device_index: u32,
+        protocol_address: [u8; IP_PROTOCOL_LENGTH_USIZE],
+    ) -> Result<()> {
+        let target = u32::from_be_bytes(protocol_address);
+        let interfaces = context.ip_interfaces(device_index)?;
+        let interface = interfaces
+            .iter()
+            .find(|interface| interface.contains(target))
+            .or_else(|| interfaces.first())
+            .ok_or_else(|| anyhow::anyhow!("no ip interface, dev={}", device_index))?;
+        let hardware_address = ethernet_address(context, device_index)?;
+        let request = ARPEthernetIPPacket::request(
+            hardware_address,
+            interface.unicast.to_be_bytes(),
+            protocol_address,
+        );
+        debug!(
+            "request, dev={}, tpa={}",
+            device_index,
+            Ipv4Addr::from(protocol_address)
+        );
+        context.transmit(
+            device_index,
+            NET_PROTOCOL_ARP,
+            request.serialize(),
+            &ETHERNET_ADDRESS_BROADCAST,
+        )
+    }
+}
+
+fn ethernet_address(
+    context: &NetDeviceContext,
+    device_index: u32,
+) -> Result<[u8; ETHERNET_HARDWARE_LENGTH_USIZE]> {
+    context
+        .hardware_address(device_index)?
+        .try_into()
+        .map_err(|_| anyhow::anyhow!("not an ethernet device, dev={}", device_index))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::icmp::SocketError;
+    use crate::ip::{IPProtocol, IP_ADDRESS_ANY};
+    use crate::net::tests::{ethernet_context, Captured, ETHERNET_ADDRESS};
+    use std::sync::{Arc, Mutex};
+
+    const HARDWARE_ADDRESS: [u8; ETHERNET_HARDWARE_LENGTH_USIZE] = [0x02, 0, 0, 0, 0, 0x01];
+
+    /// A request for 192.0.2.1 from 192.0.2.2 at `HARDWARE_ADDRESS`.
+    fn request() -> ARPEthernetIPPacket {
+        ARPEthernetIPPacket::request(HARDWARE_ADDRESS, [192, 0, 2, 2], [192, 0, 2, 1])
+    }
+
+    #[test]
+    fn packet_round_trips() {
+        let data = request().serialize();
+        assert_eq!(data.len(), ARPEthernetIPPacket::LENGTH);
+        assert_eq!(
+            ARPEthernetIPPacket::parse_ethernet_ip(&data).unwrap(),
+            request()
+        );
+        let reply = request().reply([0x02, 0, 0, 0, 0, 0x02]);
+        assert_eq!(
+            ARPEthernetIPPacket::parse_ethernet_ip(&reply.serialize()).unwrap(),
+            reply
+        );
+        // Ethernet pads the packet to the minimum frame size.
+        let mut padded = data.clone();
+        padded.resize(46, 0);
+        assert_eq!(
+            ARPEthernetIPPacket::parse_ethernet_ip(&padded).unwrap(),
+            request()
+        );
+    }
+
+    #[test]
+    fn packet_rejects_invalid_fields() {
+        let valid = request().serialize();
+        let with = |offset: usize, value: u8| {
+            let mut data = valid.clone();
+            data[offset] = value;
+            data
+        };
+        let cases = [
+            (valid[..ARP_HEADER_LENGTH - 1].to_vec(), "too short header"),
+            (with(1, 6), "unsupported hardware type"),
+            (with(2, 0x86), "unsupported protocol type"),
+            (with(4, 8), "invalid hardware length"),
+            (with(5, 16), "invalid protocol length"),
+            (with(7, 0), "invalid opcode"),
+            (with(7, 3), "invalid opcode"),
+            (valid[..valid.len() - 1].to_vec(), "too short packet"),
+        ];
+        for (data, error) in cases {
+            let message = ARPEthernetIPPacket::parse_ethernet_ip(&data)
+                .unwrap_err()
+                .to_string();
+            assert!(
+                message.starts_with(error),
+                "data={:02x?}, err={}",
+                data,
+                message
+            );
+        }
+    }
+
+    const PEER: [u8; IP_PROTOCOL_LENGTH_USIZE] = [192, 0, 2, 2];
+    const PEER_HARDWARE_ADDRESS: [u8; ETHERNET_HARDWARE_LENGTH_USIZE] = [0x02, 0, 0, 0, 0, 0x02];
+
+    /// Sends a UDP datagram from port 5000 to `PEER` with `length` bytes of data.
+    fn send(context: &NetDeviceContext, length: usize) -> Result<()> {
+        let mut data = vec![0x13, 0x88, 0x82, 0x9a];
+        data.extend_from_slice(&((8 + length) as u16).to_be_bytes());
+        data.extend_from_slice(&[0, 0]);
+        data.resize(8 + length, 0);
+        context.ip_controller().output(
+            context,
+            IPProtocol::UDP,
+            data,
+            IP_ADDRESS_ANY,
+            u32::from_be_bytes(PEER),
+        )
+    }
+
+    /// The ARP requests for `PEER` among the packets of `sent`.
+    fn requests(sent: &Captured) -> usize {
+        sent.lock()
+            .unwrap()
+            .iter()
+            .filter_map(|data| ARPEthernetIPPacket::parse_ethernet_ip(data).ok())
+            .filter(|packet| {
+                packet.header.opcode == ARP_OPCODE_REQUEST && packet.target_protocol_address == PEER
+            })
+            .count()
+    }
+
+    /// Makes the last request for `PEER` look older than `ARP_REQUEST_RETRY_INTERVAL`.
+    fn age(context: &ARPEthernetIPContext) {
+        let mut cache = context.cache.write().unwrap();
+        let entry = cache.get_mut(&PEER).unwrap();
+        entry.requested = entry
+            .requested
+            .and_then(|requested| requested.checked_sub(ARP_REQUEST_RETRY_INTERVAL));
+    }
+
+    fn entry(context: &ARPEthernetIPContext) -> ARPEthernetIPCacheEntryInfo {
+        context
+            .entries()
+            .unwrap()
+            .into_iter()
+            .find(|entry| entry.protocol_address == PEER)
+            .unwrap()
+    }
+
+    #[test]
+    fn reply_releases_pending_packets() {
+        let (context, sent) = ethernet_context(&["192.0.2.1/24"]);
+        let arp = context.arp_context();
+        send(&context, 8).unwrap();
+        send(&context, 8).unwrap();
+        assert_eq!(requests(&sent[0]), 1);
+        assert_eq!(entry(arp).state, ARPCacheState::Incomplete);
+        assert_eq!(entry(arp).pending, 2);
+        let reply = ARPEthernetIPPacket::new(
+            ARP_HARDWARE_TYPE_ETHERNET,
+            ARP_PROTOCOL_TYPE_IP,
+            ARP_OPCODE_REPLY,
+            PEER_HARDWARE_ADDRESS,
+            PEER,
+            ETHERNET_ADDRESS,
+            [192, 0, 2, 1],
+        );
+        arp.input(&context, 0, &reply.serialize()).unwrap();
+        assert_eq!(entry(arp).state, ARPCacheState::Resolved);
+        assert_eq!(entry(arp).pending, 0);
+        assert_eq!(arp.lookup(PEER).unwrap(), Some(PEER_HARDWARE_ADDRESS));
+        let released: Vec<IPPacket> = sent[0]
+            .lock()
+            .unwrap()
+            .iter()
+            .filter_map(|data| IPPacket::parse(data).ok())
+            .collect();
+        assert_eq!(released.len(), 2);
+        // Once resolved, packets go out right away.
+        send(&context, 8).unwrap();
+        assert_eq!(sent[0].lock().unwrap().len(), 4);
+        assert_eq!(requests(&sent[0]), 1);
+    }
+
+    #[test]
+    fn resolution_fails_after_retries() {
+        let (context, sent) = ethernet_context(&["192.0.2.1/24"]);
+        let arp = context.arp_context();
+        let errors = Arc::new(Mutex::new(Vec::new()));
+        let reported = errors.clone();
+        context
+            .icmp_controller()
+            .register_endpoint(
+                IPProtocol::UDP,
+                IP_ADDRESS_ANY,
+                5000,
+                Box::new(move |_, report| {
+                    reported.lock().unwrap().push(report.error);
+                    Ok(())
+                }),
+            )
+            .unwrap();
+        send(&context, 8).unwrap();
+        // Nothing is due before the retry interval.
+        arp.timer(&context).unwrap();
+        assert_eq!(requests(&sent[0]), 1);
+        for retry in 1..ARP_REQUEST_RETRY_MAX {
+            age(arp);
+            arp.timer(&context).unwrap();
+            assert_eq!(requests(&sent[0]), 1 + retry as usize);
+            assert_eq!(entry(arp).state, ARPCacheState::Incomplete);
+        }
+        assert!(errors.lock().unwrap().is_empty());
+        age(arp);
+        arp.timer(&context).unwrap();
+        assert_eq!(requests(&sent[0]), ARP_REQUEST_RETRY_MAX as usize);
+        assert_eq!(entry(arp).state, ARPCacheState::Failed);
+        assert_eq!(entry(arp).pending, 0);
+        assert_eq!(*errors.lock().unwrap(), [SocketError::HostUnreachable]);
+        // The failure is remembered for a while.
+        let e = send(&context, 8).unwrap_err();
+        assert_eq!(e.downcast_ref::<ARPError>(), Some(&ARPError::Unreachable));
+        assert_eq!(requests(&sent[0]), ARP_REQUEST_RETRY_MAX as usize);
+    }
+
+    #[test]
+    fn queue_takes_all_fragments_or_none() {
+        let (context, _) = ethernet_context(&["192.0.2.1/24"]);
+        let arp = context.arp_context();
+        let queued = || -> usize {
+            let cache = arp.cache.read().unwrap();
+            cache[&PEER].pending.iter().map(Vec::len).sum()
+        };
+        send(&context, 30000).unwrap();
+        let fragments = entry(arp).pending;
+        let datagram = queued();
+        assert!(fragments > 1);
+        let e = loop {
+            if let Err(e) = send(&context, 30000) {
+                break e;
+            }
+        };
+        assert_eq!(e.downcast_ref::<ARPError>(), Some(&ARPError::QueueFull));
+        assert_eq!(entry(arp).pending % fragments, 0);
+        assert!(queued() <= ARP_PENDING_QUEUE_BYTES_MAX);
+        assert!(queued() + datagram > ARP_PENDING_QUEUE_BYTES_MAX);
+    }
 }
@@ -0,0 +1,90 @@
+//! Device setup shared by the command-line tools.
+
+use std::{net::Ipv4Addr, sync::Arc};
+
+use anyhow::Result;
+use rust_tcp_ip_stack::{
+    ip::IPInterface,
+    net::{LoopbackNetDevice, NetDeviceContext, TapNetDevice, NET_PROTOCOL_ARP, NET_PROTOCOL_IP},
+};
+
+pub const DEVICE_USAGE: &str = "[--tap name --address cidr [--gateway address] [--mac address]]";
+pub const DEVICE_HELP: &str =
+    "Without --tap, the stack runs on a loopback device with 127.0.0.1/8.";
+const TAP_HARDWARE_ADDRESS_DEFAULT: [u8; 6] = [0x00, 0x00, 0x5e, 0x00, 0x53, 0x01];
+
+/// The device the stack of a tool runs on, a TAP device or else a loopback.
+pub struct DeviceArguments {
+    tap: Option<String>,
+    address: Option<IPInterface>,
+    gateway: Option<u32>,
+    mac: [u8; 6],
+}
+
+impl Default for DeviceArguments {
+    fn default() -> Self {
+        DeviceArguments {
+            tap: None,
+            address: None,
+            gateway: None,
+            mac: TAP_HARDWARE_ADDRESS_DEFAULT,
+        }
+    }
+}
+
+impl DeviceArguments {
+    /// Takes `arg` if it is one of the device flags, reading its value with
+    /// `value`. Returns whether it was.
+    pub fn parse(&mut self, arg: &str, value: &mut impl FnMut() -> Result<String>) -> Result<bool> {
+        match arg {
+            "--tap" => self.tap = Some(value()?),
+            "--address" => self.address = Some(IPInterface::parse(&value()?)?),
+            "--gateway" => self.gateway = Some(u32::from(value()?.parse::<Ipv4Addr>()?)),
+            "--mac" => {
+                let value = value()?;
+                let bytes = value
+                    .split(':')
+                    .map(|byte| u8::from_str_radix(byte, 16))
+                    .collect::<Result<Vec<u8>, _>>()?;
+                self.mac = bytes
+                    .try_into()
+                    .map_err(|_| anyhow::anyhow!("invalid mac address, mac={}", value))?;
+            }
+            _ => return Ok(false),
+        }
+        Ok(true)
+    }
+
+    /// Checks the flags once all of them were parsed.
+    pub fn validate(&self) -> Result<()> {
+        if self.tap.is_some() && self.address.is_none() {
+            return Err(anyhow::anyhow!("--tap requires --address"));
+        }
+        Ok(())
+    }
+
+    /// Builds and runs a context on the device.
+    pub fn setup(&self) -> Result<Arc<NetDeviceContext>> {
+        let context = NetDeviceContext::new()?;
+        context.init()?;
+        match (&self.tap, self.address) {
+            (Some(name), Some(address)) => {
+                context.register(Box::new(TapNetDevice::new(name, self.mac)), context.clone())?;
+                context.add_ip_interface(0, address)?;
+            }
+            _ => {
+                context.register(Box::new(LoopbackNetDevice::new()), context.clone())?;
+                context.add_ip_interface(0, IPInterface::parse("127.0.0.1/8")?)?;
+            }
+        }
+        if let Some(gateway) = self.gateway {
+            context
+                .ip_controller()
+                .add_default_route(gateway, None, 0)?;
+        }
+        context.register_protocol(NET_PROTOCOL_IP)?;
+        context.register_protocol(NET_PROTOCOL_ARP)?;
+        context.run()?;
+        Ok(context)
+    }
+}
@@ -0,0 +1,177 @@
+mod common;
+
+use std::{env, net::Ipv4Addr, process, time::Duration};
+
+use anyhow::Result;
+use common::{DeviceArguments, DEVICE_HELP, DEVICE_USAGE};
+use rust_tcp_ip_stack::{
+    icmp::{
+        ICMP_CODE_FRAGMENTATION_NEEDED, ICMP_CODE_HOST_UNREACHABLE, ICMP_CODE_NET_UNREACHABLE,
+        ICMP_CODE_PORT_UNREACHABLE, ICMP_CODE_PROTOCOL_UNREACHABLE,
+        ICMP_CODE_REASSEMBLY_TIME_EXCEEDED, ICMP_CODE_TTL_EXCEEDED, ICMP_TYPE_DEST_UNREACHABLE,
+        ICMP_TYPE_PARAMETER_PROBLEM, ICMP_TYPE_TIME_EXCEEDED,
+    },
+    ping::{self, PingOptions, PingResult},
+};
+
+fn usage() -> String {
+    format!(
+        "usage: ping [-c count] [-i interval] [-s size] [-t ttl] [-W timeout]
+            {} destination
+
+{}",
+        DEVICE_USAGE, DEVICE_HELP
+    )
+}
+
+struct Arguments {
+    options: PingOptions,
+    device: DeviceArguments,
+    destination: u32,
+}
+
+fn parse_arguments() -> Result<Arguments> {
+    let mut options = PingOptions::default();
+    let mut device = DeviceArguments::default();
+    let mut destination = None;
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let mut value = || {
+            args.next()
+                .ok_or_else(|| anyhow::anyhow!("missing value, option={}", arg))
+        };
+        match arg.as_str() {
+            "-c" => options.count = value()?.parse()?,
+            "-i" => options.interval = Duration::from_secs_f64(value()?.parse()?),
+            "-s" => options.size = value()?.parse()?,
+            "-t" => options.ttl = value()?.parse()?,
+            "-W" => options.timeout = Duration::from_secs_f64(value()?.parse()?),
+            _ if device.parse(&arg, &mut value)? => {}
+            "-h" | "--help" => {
+                println!("{}", usage());
+                process::exit(0);
+            }
+            _ if destination.is_none() && !arg.starts_with('-') => {
+                destination = Some(u32::from(arg.parse::<Ipv4Addr>()?));
+            }
+            _ => return Err(anyhow::anyhow!("unexpected argument, arg={}", arg)),
+        }
+    }
+    let destination = destination.ok_or_else(|| anyhow::anyhow!("missing destination"))?;
+    device.validate()?;
+    Ok(Arguments {
+        options,
+        device,
+        destination,
+    })
+}
+
+/// Wording of the `ping` command for an ICMP error.
+fn describe(icmp_type: u8, code: u8) -> String {
+    match (icmp_type, code) {
+        (ICMP_TYPE_DEST_UNREACHABLE, ICMP_CODE_NET_UNREACHABLE) => {
+            "Destination Net Unreachable".into()
+        }
+        (ICMP_TYPE_DEST_UNREACHABLE, ICMP_CODE_HOST_UNREACHABLE) => {
+            "Destination Host Unreachable".into()
+        }
+        (ICMP_TYPE_DEST_UNREACHABLE, ICMP_CODE_PROTOCOL_UNREACHABLE) => {
+            "Destination Protocol Unreachable".into()
+        }
+        (ICMP_TYPE_DEST_UNREACHABLE, ICMP_CODE_PORT_UNREACHABLE) => {
+            "Destination Port Unreachable".into()
+        }
+        (ICMP_TYPE_DEST_UNREACHABLE, ICMP_CODE_FRAGMENTATION_NEEDED) => "Frag needed".into(),
+        (ICMP_TYPE_TIME_EXCEEDED, ICMP_CODE_TTL_EXCEEDED) => "Time to live exceeded".into(),
+        (ICMP_TYPE_TIME_EXCEEDED, ICMP_CODE_REASSEMBLY_TIME_EXCEEDED) => {
+            "Frag reassembly time exceeded".into()
+        }
+        (ICMP_TYPE_PARAMETER_PROBLEM, _) => "Parameter problem".into(),
+        _ => format!("Bad ICMP type: {}, code: {}", icmp_type, code),
+    }
+}
+
+fn main() -> Result<()> {
+    env_logger::init();
+    let arguments = match parse_arguments() {
+        Ok(arguments) => arguments,
+        Err(e) => {
+            eprintln!("{}\n{}", e, usage());
+            process::exit(2);
+        }
+    };
+    let context = arguments.device.setup()?;
+    let destination = Ipv4Addr::from(arguments.destination);
+    println!(
+        "PING {} ({}) {}({}) bytes of data.",
+        destination,
+        destination,
+        arguments.options.size,
+        ping::datagram_length(arguments.options.size)
+    );
+    let report = ping::ping(
+        &context,
+        arguments.destination,
+        &arguments.options,
+        |result| match result {
+            PingResult::Reply {
+                sequence,
+                from,
+                size,
+                ttl,
+                rtt,
+            } => println!(
+                "{} bytes from {}: icmp_seq={} ttl={} time={:.3} ms",
+                size + 8,
+                Ipv4Addr::from(*from),
+                sequence,
+                ttl,
+                rtt.as_secs_f64() * 1000.0
+            ),
+            PingResult::Error {
+                sequence,
+                from,
+                icmp_type,
+                code,
+                ..
+            } => println!(
+                "From {} icmp_seq={} {}",
+                Ipv4Addr::from(*from),
+                sequence,
+                describe(*icmp_type, *code)
+            ),
+            PingResult::Timeout { sequence } => {
+                println!("Request timeout for icmp_seq {}", sequence)
+            }
+        },
+    );
+    context.shutdown()?;
+    let statistics = report?.statistics;
+    println!("\n--- {} ping statistics ---", destination);
+    print!(
+        "{} packets transmitted, {} received, ",
+        statistics.transmitted, statistics.received
+    );
+    if statistics.errors > 0 {
+        print!("+{} errors, ", statistics.errors);
+    }
+    println!("{:.0}% packet loss", statistics.loss());
+    if let (Some(min), Some(avg), Some(max), Some(mdev)) = (
+        statistics.rtt_min,
+        statistics.rtt_avg,
+        statistics.rtt_max,
+        statistics.rtt_mdev,
+    ) {
+        println!(
+            "rtt min/avg/max/mdev = {:.3}/{:.3}/{:.3}/{:.3} ms",
+            min.as_secs_f64() * 1000.0,
+            avg.as_secs_f64() * 1000.0,
+            max.as_secs_f64() * 1000.0,
+            mdev.as_secs_f64() * 1000.0
+        );
+    }
+    if statistics.received == 0 {
+        process::exit(1);
+    }
+    Ok(())
+}
@@ -0,0 +1,154 @@
+mod common;
+
+use std::{env, net::Ipv4Addr, process, time::Duration};
+
+use anyhow::Result;
+use common::{DeviceArguments, DEVICE_HELP, DEVICE_USAGE};
+use rust_tcp_ip_stack::{
+    icmp::{
+        ICMP_CODE_FRAGMENTATION_NEEDED, ICMP_CODE_HOST_UNREACHABLE, ICMP_CODE_NET_UNREACHABLE,
+        ICMP_CODE_PORT_UNREACHABLE, ICMP_CODE_PROTOCOL_UNREACHABLE, ICMP_TYPE_DEST_UNREACHABLE,
+    },
+    traceroute::{self, TracerouteHop, TracerouteMethod, TracerouteOptions},
+};
+
+fn usage() -> String {
+    format!(
+        "usage: traceroute [-I | -U] [-f first_ttl] [-m max_ttl] [-q nqueries]
+            [-w waittime] [-p port]
+            {}
+            host [packetlen]
+
+{}",
+        DEVICE_USAGE, DEVICE_HELP
+    )
+}
+
+struct Arguments {
+    options: TracerouteOptions,
+    device: DeviceArguments,
+    destination: u32,
+}
+
+fn parse_arguments() -> Result<Arguments> {
+    let mut options = TracerouteOptions::default();
+    let mut device = DeviceArguments::default();
+    let mut destination = None;
+    let mut length = None;
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let mut value = || {
+            args.next()
+                .ok_or_else(|| anyhow::anyhow!("missing value, option={}", arg))
+        };
+        match arg.as_str() {
+            "-I" => options.method = TracerouteMethod::ICMP,
+            "-U" => options.method = TracerouteMethod::UDP,
+            "-f" => options.first_ttl = value()?.parse()?,
+            "-m" => options.max_ttl = value()?.parse()?,
+            "-q" => options.probes = value()?.parse()?,
+            "-w" => options.timeout = Duration::from_secs_f64(value()?.parse()?),
+            "-p" => options.port = value()?.parse()?,
+            _ if device.parse(&arg, &mut value)? => {}
+            "-h" | "--help" => {
+                println!("{}", usage());
+                process::exit(0);
+            }
+            _ if destination.is_none() && !arg.starts_with('-') => {
+                destination = Some(u32::from(arg.parse::<Ipv4Addr>()?));
+            }
+            _ if length.is_none() && !arg.starts_with('-') => {
+                length = Some(arg.parse::<usize>()?);
+            }
+            _ => return Err(anyhow::anyhow!("unexpected argument, arg={}", arg)),
+        }
+    }
+    let destination = destination.ok_or_else(|| anyhow::anyhow!("missing destination"))?;
+    device.validate()?;
+    if options.first_ttl == 0 || options.first_ttl > options.max_ttl {
+        return Err(anyhow::anyhow!(
+            "invalid ttl range, first={}, max={}",
+            options.first_ttl,
+            options.max_ttl
+        ));
+    }
+    if let Some(length) = length {
+        // packetlen counts the headers, as with the traceroute command.
+        let headers = traceroute::datagram_length(options.method, 0);
+        options.size = length.checked_sub(headers).ok_or_else(|| {
+            anyhow::anyhow!("too short packetlen, len={}, min={}", length, headers)
+        })?;
+    }
+    Ok(Arguments {
+        options,
+        device,
+        destination,
+    })
+}
+
+/// Annotation of the `traceroute` command for an answer other than a Time
+/// Exceeded, empty for the Port Unreachable expected from the destination.
+fn annotation(icmp_type: u8, code: u8) -> String {
+    match (icmp_type, code) {
+        (ICMP_TYPE_DEST_UNREACHABLE, ICMP_CODE_NET_UNREACHABLE) => " !N".into(),
+        (ICMP_TYPE_DEST_UNREACHABLE, ICMP_CODE_HOST_UNREACHABLE) => " !H".into(),
+        (ICMP_TYPE_DEST_UNREACHABLE, ICMP_CODE_PROTOCOL_UNREACHABLE) => " !P".into(),
+        (ICMP_TYPE_DEST_UNREACHABLE, ICMP_CODE_PORT_UNREACHABLE) => String::new(),
+        (ICMP_TYPE_DEST_UNREACHABLE, ICMP_CODE_FRAGMENTATION_NEEDED) => " !F".into(),
+        (ICMP_TYPE_DEST_UNREACHABLE, code) => format!(" !<{}>", code),
+        _ => String::new(),
+    }
+}
+
+fn print_hop(hop: &TracerouteHop) {
+    let mut line = format!("{:2} ", hop.ttl);
+    let mut from = None;
+    for probe in &hop.probes {
+        match probe {
+            Some(probe) => {
+                if from != Some(probe.from) {
+                    line.push_str(&format!(" {}", Ipv4Addr::from(probe.from)));
+                    from = Some(probe.from);
+                }
+                line.push_str(&format!(
+                    "  {:.3} ms{}",
+                    probe.rtt.as_secs_f64() * 1000.0,
+                    annotation(probe.icmp_type, probe.code)
+                ));
+            }
+            None => line.push_str(" *"),
+        }
+    }
+    println!("{}", line);
+}
+
+fn main() -> Result<()> {
+    env_logger::init();
+    let arguments = match parse_arguments() {
+        Ok(arguments) => arguments,
+        Err(e) => {
+            eprintln!("{}\n{}", e, usage());
+            process::exit(2);
+        }
+    };
+    let context = arguments.device.setup()?;
+    let destination = Ipv4Addr::from(arguments.destination);
+    println!(
+        "traceroute to {} ({}), {} hops max, {} byte packets",
+        destination,
+        destination,
+        arguments.options.max_ttl,
+        traceroute::datagram_length(arguments.options.method, arguments.options.size)
+    );
+    let report = traceroute::traceroute(
+        &context,
+        arguments.destination,
+        &arguments.options,
+        print_hop,
+    );
+    context.shutdown()?;
+    if !report?.reached {
+        process::exit(1);
+    }
+    Ok(())
+}
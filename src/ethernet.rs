@@ -1,3 +1,142 @@
+use anyhow::Result;
+use log::debug;
+
+use crate::net::NetDeviceQueueEntry;
+
 pub const ETHERNET_ADDRESS_LENGTH: u8 = 6;
+pub const ETHERNET_HEADER_LENGTH: usize = 14;
+/// Minimum frame size on the wire, excluding the FCS.
+pub const ETHERNET_FRAME_SIZE_MIN: usize = 60;
+pub const ETHERNET_FRAME_SIZE_MAX: usize = 1514;
+pub const ETHERNET_PAYLOAD_SIZE_MIN: usize = ETHERNET_FRAME_SIZE_MIN - ETHERNET_HEADER_LENGTH;
+pub const ETHERNET_PAYLOAD_SIZE_MAX: usize = ETHERNET_FRAME_SIZE_MAX - ETHERNET_HEADER_LENGTH;
+
+pub const ETHERNET_ADDRESS_ANY: [u8; 6] = [0x00; 6];
+pub const ETHERNET_ADDRESS_BROADCAST: [u8; 6] = [0xff; 6];
 
 pub const ETHERNET_TYPE_IP: u16 = 0x0800;
+pub const ETHERNET_TYPE_ARP: u16 = 0x0806;
+pub const ETHERNET_TYPE_IPV6: u16 = 0x86dd;
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct EthernetFrame {
+    destination_address: [u8; 6],
+    source_address: [u8; 6],
+    ether_type: u16,
+    payload: Vec<u8>,
+}
+
+impl EthernetFrame {
+    pub fn new(
+        destination_address: [u8; 6],
+        source_address: [u8; 6],
+        ether_type: u16,
+        payload: Vec<u8>,
+    ) -> Self {
+        EthernetFrame {
+            destination_address,
+            source_address,
+            ether_type,
+            payload,
+        }
+    }
+    /// The payload keeps any trailing padding, upper layers trim it with their own length field.
+    pub fn parse(data: &[u8]) -> Result<Self> {
+        if data.len() < ETHERNET_HEADER_LENGTH {
+            return Err(anyhow::anyhow!("too short frame, len={}", data.len()));
+        }
+        if data.len() > ETHERNET_FRAME_SIZE_MAX {
+            return Err(anyhow::anyhow!("too long frame, len={}", data.len()));
+        }
+        let mut destination_address = [0; 6];
+        destination_address.copy_from_slice(&data[0..6]);
+        let mut source_address = [0; 6];
+        source_address.copy_from_slice(&data[6..12]);
+        let ether_type = u16::from_be_bytes([data[12], data[13]]);
+        Ok(EthernetFrame {
+            destination_address,
+            source_address,
+            ether_type,
+            payload: data[ETHERNET_HEADER_LENGTH..].to_vec(),
+        })
+    }
+    pub fn serialize(&self) -> Result<Vec<u8>> {
+        if self.payload.len() > ETHERNET_PAYLOAD_SIZE_MAX {
+            return Err(anyhow::anyhow!(
+                "too long payload, len={}",
+                self.payload.len()
+            ));
+        }
+        let mut data = Vec::with_capacity(ETHERNET_HEADER_LENGTH + self.payload.len());
+        data.extend_from_slice(&self.destination_address);
+        data.extend_from_slice(&self.source_address);
+        data.extend_from_slice(&self.ether_type.to_be_bytes());
+        data.extend_from_slice(&self.payload);
+        if data.len() < ETHERNET_FRAME_SIZE_MIN {
+            data.resize(ETHERNET_FRAME_SIZE_MIN, 0);
+        }
+        Ok(data)
+    }
+    pub fn destination_address(&self) -> [u8; 6] {
+        self.destination_address
+    }
+    pub fn source_address(&self) -> [u8; 6] {
+        self.source_address
+    }
+    pub fn ether_type(&self) -> u16 {
+        self.ether_type
+    }
+    pub fn payload(&self) -> &[u8] {
+        &self.payload
+    }
+}
+
+/// Strips the Ethernet header of a received frame so that the payload can be handed to
+/// `NetDeviceContext::input`. Frames addressed to other stations are dropped.
+pub fn input_helper(hardware_address: [u8; 6], data: &[u8]) -> Result<Option<NetDeviceQueueEntry>> {
+    let frame = EthernetFrame::parse(data)?;
+    if frame.destination_address != hardware_address
+        && frame.destination_address != ETHERNET_ADDRESS_BROADCAST
+    {
+        return Ok(None);
+    }
+    debug!(
+        "input, dst={}, src={}, type=0x{:04x}, len={}",
+        address_to_string(&frame.destination_address),
+        address_to_string(&frame.source_address),
+        frame.ether_type,
+        frame.payload.len()
+    );
+    Ok(Some(NetDeviceQueueEntry {
+        net_protocol_type: frame.ether_type,
+        data: frame.payload,
+    }))
+}
+
+/// Builds the frame to be written to the medium for `data` of type `ether_type`.
+pub fn transmit_helper(
+    hardware_address: [u8; 6],
+    destination: &[u8],
+    ether_type: u16,
+    data: Vec<u8>,
+) -> Result<Vec<u8>> {
+    let destination_address: [u8; 6] = destination
+        .try_into()
+        .map_err(|_| anyhow::anyhow!("invalid destination, len={}", destination.len()))?;
+    debug!(
+        "output, dst={}, src={}, type=0x{:04x}, len={}",
+        address_to_string(&destination_address),
+        address_to_string(&hardware_address),
+        ether_type,
+        data.len()
+    );
+    EthernetFrame::new(destination_address, hardware_address, ether_type, data).serialize()
+}
+
+pub fn address_to_string(address: &[u8]) -> String {
+    address
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect::<Vec<String>>()
+        .join(":")
+}
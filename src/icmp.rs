@@ -0,0 +1,843 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    net::Ipv4Addr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        RwLock,
+    },
+};
+
+use anyhow::Result;
+use log::debug;
+
+use crate::ip::{
+    checksum, IPPacket, IPProtocol, IP_ADDRESS_ANY, IP_ADDRESS_BROADCAST, IP_HEADER_LENGTH_MIN,
+};
+use crate::net::NetDeviceContext;
+
+pub const ICMP_HEADER_LENGTH: usize = 8;
+pub const ICMP_TYPE_ECHO_REPLY: u8 = 0;
+pub const ICMP_TYPE_DEST_UNREACHABLE: u8 = 3;
+pub const ICMP_TYPE_SOURCE_QUENCH: u8 = 4;
+pub const ICMP_TYPE_REDIRECT: u8 = 5;
+pub const ICMP_TYPE_ECHO_REQUEST: u8 = 8;
+pub const ICMP_TYPE_TIME_EXCEEDED: u8 = 11;
+pub const ICMP_TYPE_PARAMETER_PROBLEM: u8 = 12;
+pub const ICMP_CODE_NET_UNREACHABLE: u8 = 0;
+pub const ICMP_CODE_HOST_UNREACHABLE: u8 = 1;
+pub const ICMP_CODE_PROTOCOL_UNREACHABLE: u8 = 2;
+pub const ICMP_CODE_PORT_UNREACHABLE: u8 = 3;
+pub const ICMP_CODE_FRAGMENTATION_NEEDED: u8 = 4;
+pub const ICMP_CODE_TTL_EXCEEDED: u8 = 0;
+pub const ICMP_CODE_REASSEMBLY_TIME_EXCEEDED: u8 = 1;
+/// Bytes of the data of the offending datagram quoted after its header (RFC 792).
+const ICMP_ERROR_QUOTE_LENGTH: usize = 8;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum ICMPError {
+    /// The message is shorter than the ICMP header.
+    TooShort { len: usize },
+    /// The checksum does not verify, `checksum` is the value carried by the message.
+    InvalidChecksum { checksum: u16 },
+}
+impl fmt::Display for ICMPError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ICMPError::TooShort { len } => write!(f, "too short message, len={}", len),
+            ICMPError::InvalidChecksum { checksum } => {
+                write!(f, "invalid checksum, checksum=0x{:04x}", checksum)
+            }
+        }
+    }
+}
+impl std::error::Error for ICMPError {}
+
+/// Counters of the ICMP layer.
+#[derive(Debug, Default)]
+pub struct ICMPStatistics {
+    pub received: AtomicU64,
+    /// Messages dropped because they were truncated or failed the checksum.
+    pub errors: AtomicU64,
+    pub echo_requests: AtomicU64,
+    pub echo_replies: AtomicU64,
+    pub sent: AtomicU64,
+    /// Errors not sent because the offending datagram was itself an ICMP error, a
+    /// broadcast or a non-first fragment.
+    pub errors_suppressed: AtomicU64,
+    /// Errors received and handed to the endpoint that sent the offending datagram.
+    pub errors_delivered: AtomicU64,
+    /// Errors and echo replies not sent because of the limits of the IP controller.
+    pub rate_limited: AtomicU64,
+}
+impl ICMPStatistics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+/// An ICMP message (RFC 792).
+///
+/// `rest` is the second word of the header, whose meaning depends on the type:
+/// identifier and sequence number for echoes, unused for most errors.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ICMPMessage {
+    icmp_type: u8,
+    code: u8,
+    checksum: u16,
+    rest: u32,
+    data: Vec<u8>,
+}
+
+impl ICMPMessage {
+    pub fn new(icmp_type: u8, code: u8, rest: u32, data: Vec<u8>) -> Self {
+        ICMPMessage {
+            icmp_type,
+            code,
+            checksum: 0,
+            rest,
+            data,
+        }
+    }
+    pub fn echo_request(identifier: u16, sequence: u16, data: Vec<u8>) -> Self {
+        Self::new(
+            ICMP_TYPE_ECHO_REQUEST,
+            0,
+            (identifier as u32) << 16 | sequence as u32,
+            data,
+        )
+    }
+    pub fn echo_reply(identifier: u16, sequence: u16, data: Vec<u8>) -> Self {
+        Self::new(
+            ICMP_TYPE_ECHO_REPLY,
+            0,
+            (identifier as u32) << 16 | sequence as u32,
+            data,
+        )
+    }
+    /// Builds an error message about `datagram`, quoting its header and the first
+    /// bytes of its data.
+    pub fn error(icmp_type: u8, code: u8, rest: u32, datagram: &[u8]) -> Self {
+        let header_length = datagram
+            .first()
+            .map_or(0, |&version_ihl| (version_ihl & 0x0f) as usize * 4);
+        let length = datagram.len().min(header_length + ICMP_ERROR_QUOTE_LENGTH);
+        Self::new(icmp_type, code, rest, datagram[..length].to_vec())
+    }
+    /// Whether messages of `icmp_type` report errors, as opposed to queries.
+    pub fn is_error_type(icmp_type: u8) -> bool {
+        matches!(
+            icmp_type,
+            ICMP_TYPE_DEST_UNREACHABLE
+                | ICMP_TYPE_SOURCE_QUENCH
+                | ICMP_TYPE_REDIRECT
+                | ICMP_TYPE_TIME_EXCEEDED
+                | ICMP_TYPE_PARAMETER_PROBLEM
+        )
+    }
+    /// Parses a message, verifying its checksum.
+    pub fn parse(data: &[u8]) -> Result<Self, ICMPError> {
+        if data.len() < ICMP_HEADER_LENGTH {
+            return Err(ICMPError::TooShort { len: data.len() });
+        }
+        let sum = u16::from_be_bytes([data[2], data[3]]);
+        if checksum(data, 0) != 0 {
+            return Err(ICMPError::InvalidChecksum { checksum: sum });
+        }
+        Ok(ICMPMessage {
+            icmp_type: data[0],
+            code: data[1],
+            checksum: sum,
+            rest: u32::from_be_bytes([data[4], data[5], data[6], data[7]]),
+            data: data[ICMP_HEADER_LENGTH..].to_vec(),
+        })
+    }
+    /// Serializes the message, computing its checksum.
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut data = Vec::with_capacity(ICMP_HEADER_LENGTH + self.data.len());
+        data.push(self.icmp_type);
+        data.push(self.code);
+        data.extend_from_slice(&[0, 0]);
+        data.extend_from_slice(&self.rest.to_be_bytes());
+        data.extend_from_slice(&self.data);
+        let sum = checksum(&data, 0);
+        data[2..4].copy_from_slice(&sum.to_be_bytes());
+        data
+    }
+    pub fn icmp_type(&self) -> u8 {
+        self.icmp_type
+    }
+    pub fn code(&self) -> u8 {
+        self.code
+    }
+    pub fn checksum(&self) -> u16 {
+        self.checksum
+    }
+    pub fn rest(&self) -> u32 {
+        self.rest
+    }
+    pub fn identifier(&self) -> u16 {
+        (self.rest >> 16) as u16
+    }
+    pub fn sequence(&self) -> u16 {
+        self.rest as u16
+    }
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+}
+
+/// Failure reported to an endpoint by an ICMP error, like the `ee_errno` of
+/// Linux's `IP_RECVERR`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SocketError {
+    NetworkUnreachable,
+    HostUnreachable,
+    ProtocolUnavailable,
+    ConnectionRefused,
+    /// The datagram exceeds the MTU of a hop, which announced `mtu` (0 if unknown).
+    MessageTooLong {
+        mtu: u16,
+    },
+    ProtocolError,
+}
+impl SocketError {
+    /// Maps an ICMP error to a socket error, as `icmp_err_convert` does in Linux.
+    pub fn from_icmp(icmp_type: u8, code: u8, rest: u32) -> Option<Self> {
+        let error = match (icmp_type, code) {
+            (ICMP_TYPE_DEST_UNREACHABLE, ICMP_CODE_NET_UNREACHABLE) => {
+                SocketError::NetworkUnreachable
+            }
+            (ICMP_TYPE_DEST_UNREACHABLE, ICMP_CODE_PROTOCOL_UNREACHABLE) => {
+                SocketError::ProtocolUnavailable
+            }
+            (ICMP_TYPE_DEST_UNREACHABLE, ICMP_CODE_PORT_UNREACHABLE) => {
+                SocketError::ConnectionRefused
+            }
+            (ICMP_TYPE_DEST_UNREACHABLE, ICMP_CODE_FRAGMENTATION_NEEDED) => {
+                SocketError::MessageTooLong { mtu: rest as u16 }
+            }
+            (ICMP_TYPE_DEST_UNREACHABLE, _) | (ICMP_TYPE_TIME_EXCEEDED, _) => {
+                SocketError::HostUnreachable
+            }
+            (ICMP_TYPE_PARAMETER_PROBLEM, _) => SocketError::ProtocolError,
+            _ => return None,
+        };
+        Some(error)
+    }
+    /// The matching `errno` value.
+    pub fn errno(&self) -> i32 {
+        match self {
+            SocketError::NetworkUnreachable => libc::ENETUNREACH,
+            SocketError::HostUnreachable => libc::EHOSTUNREACH,
+            SocketError::ProtocolUnavailable => libc::ENOPROTOOPT,
+            SocketError::ConnectionRefused => libc::ECONNREFUSED,
+            SocketError::MessageTooLong { .. } => libc::EMSGSIZE,
+            SocketError::ProtocolError => libc::EPROTO,
+        }
+    }
+}
+impl fmt::Display for SocketError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SocketError::NetworkUnreachable => write!(f, "network unreachable"),
+            SocketError::HostUnreachable => write!(f, "host unreachable"),
+            SocketError::ProtocolUnavailable => write!(f, "protocol not available"),
+            SocketError::ConnectionRefused => write!(f, "connection refused"),
+            SocketError::MessageTooLong { mtu } => write!(f, "message too long, mtu={}", mtu),
+            SocketError::ProtocolError => write!(f, "protocol error"),
+        }
+    }
+}
+impl std::error::Error for SocketError {}
+
+/// An ICMP error matched to the datagram it is about.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ICMPErrorReport {
+    pub error: SocketError,
+    pub icmp_type: u8,
+    pub code: u8,
+    /// Host that sent the ICMP error.
+    pub offender: u32,
+    pub protocol: IPProtocol,
+    pub local_address: u32,
+    pub local_port: u16,
+    pub remote_address: u32,
+    pub remote_port: u16,
+    /// Quoted beginning of the transport header of the offending datagram.
+    pub data: Vec<u8>,
+}
+
+/// Receives the Echo Replies of an identifier along with the datagram carrying them.
+pub type ICMPEchoHandler =
+    Box<dyn Fn(&NetDeviceContext, &IPPacket, &ICMPMessage) -> Result<()> + Send + Sync>;
+
+pub type ICMPErrorHandler =
+    Box<dyn Fn(&NetDeviceContext, &ICMPErrorReport) -> Result<()> + Send + Sync>;
+
+/// Identifies an endpoint receiving errors: protocol, local address (or
+/// `IP_ADDRESS_ANY` for all of them) and local port. For ICMP, the port is the
+/// identifier of the echoes.
+type ICMPEndpoint = (IPProtocol, u32, u16);
+
+pub struct ICMPController {
+    /// Echo Reply handlers keyed by echo identifier.
+    echo_handlers: RwLock<HashMap<u16, ICMPEchoHandler>>,
+    endpoints: RwLock<HashMap<ICMPEndpoint, ICMPErrorHandler>>,
+    statistics: ICMPStatistics,
+}
+
+impl Default for ICMPController {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ICMPController {
+    pub fn new() -> Self {
+        ICMPController {
+            echo_handlers: RwLock::new(HashMap::new()),
+            endpoints: RwLock::new(HashMap::new()),
+            statistics: ICMPStatistics::new(),
+        }
+    }
+
+    pub fn statistics(&self) -> &ICMPStatistics {
+        &self.statistics
+    }
+
+    /// Registers the `handler` of the Echo Replies carrying `identifier`.
+    pub fn register_echo_handler(&self, identifier: u16, handler: ICMPEchoHandler) -> Result<()> {
+        let mut echo_handlers = self
+            .echo_handlers
+            .write()
+            .map_err(|_| anyhow::anyhow!("Failed to write lock"))?;
+        if echo_handlers.contains_key(&identifier) {
+            return Err(anyhow::anyhow!(
+                "already registered, identifier={}",
+                identifier
+            ));
+        }
+        echo_handlers.insert(identifier, handler);
+        Ok(())
+    }
+
+    pub fn unregister_echo_handler(&self, identifier: u16) -> Result<()> {
+        self.echo_handlers
+            .write()
+            .map_err(|_| anyhow::anyhow!("Failed to write lock"))?
+            .remove(&identifier)
+            .map(|_| ())
+            .ok_or_else(|| anyhow::anyhow!("not registered, identifier={}", identifier))
+    }
+
+    /// Registers the `handler` of the errors about the datagrams sent from
+    /// `local_address`:`local_port` over `protocol`, like a socket with
+    /// `IP_RECVERR` set. `local_address` can be `IP_ADDRESS_ANY`.
+    pub fn register_endpoint(
+        &self,
+        protocol: IPProtocol,
+        local_address: u32,
+        local_port: u16,
+        handler: ICMPErrorHandler,
+    ) -> Result<()> {
+        let mut endpoints = self
+            .endpoints
+            .write()
+            .map_err(|_| anyhow::anyhow!("Failed to write lock"))?;
+        let endpoint = (protocol, local_address, local_port);
+        if endpoints.contains_key(&endpoint) {
+            return Err(anyhow::anyhow!(
+                "already registered, protocol={:?}, local={}:{}",
+                protocol,
+                Ipv4Addr::from(local_address),
+                local_port
+            ));
+        }
+        endpoints.insert(endpoint, handler);
+        debug!(
+            "endpoint registered, protocol={:?}, local={}:{}",
+            protocol,
+            Ipv4Addr::from(local_address),
+            local_port
+        );
+        Ok(())
+    }
+
+    pub fn unregister_endpoint(
+        &self,
+        protocol: IPProtocol,
+        local_address: u32,
+        local_port: u16,
+    ) -> Result<()> {
+        self.endpoints
+            .write()
+            .map_err(|_| anyhow::anyhow!("Failed to write lock"))?
+            .remove(&(protocol, local_address, local_port))
+            .map(|_| ())
+            .ok_or_else(|| {
+                anyhow::anyhow!(
+                    "not registered, protocol={:?}, local={}:{}",
+                    protocol,
+                    Ipv4Addr::from(local_address),
+                    local_port
+                )
+            })
+    }
+
+    /// Decodes the datagram quoted by the error `message` and hands the error to
+    /// the endpoint that sent it, if any.
+    fn deliver_error(
+        &self,
+        context: &NetDeviceContext,
+        offender: u32,
+        message: &ICMPMessage,
+    ) -> Result<()> {
+        let Some(error) = SocketError::from_icmp(message.icmp_type, message.code, message.rest)
+        else {
+            return Ok(());
+        };
+        let quote = &message.data;
+        if quote.len() < IP_HEADER_LENGTH_MIN {
+            debug!("too short quote, len={}", quote.len());
+            return Ok(());
+        }
+        let header_length = (quote[0] & 0x0f) as usize * 4;
+        let Some(data) = quote.get(header_length..header_length + 8) else {
+            debug!("too short quote, len={}", quote.len());
+            return Ok(());
+        };
+        let protocol = IPProtocol::from(quote[9]);
+        let (local_port, remote_port) = match protocol {
+            // The echo identifier follows the type, code and checksum.
+            IPProtocol::ICMP => (u16::from_be_bytes([data[4], data[5]]), 0),
+            _ => (
+                u16::from_be_bytes([data[0], data[1]]),
+                u16::from_be_bytes([data[2], data[3]]),
+            ),
+        };
+        let report = ICMPErrorReport {
+            error,
+            icmp_type: message.icmp_type,
+            code: message.code,
+            offender,
+            protocol,
+            local_address: u32::from_be_bytes([quote[12], quote[13], quote[14], quote[15]]),
+            local_port,
+            remote_address: u32::from_be_bytes([quote[16], quote[17], quote[18], quote[19]]),
+            remote_port,
+            data: data.to_vec(),
+        };
+        // Only datagrams we sent have endpoints here; an error quoting another
+        // source is forged or misrouted.
+        if context.lookup_ip_interface(report.local_address)?.is_none() {
+            debug!(
+                "not sent by us, src={}, offender={}",
+                Ipv4Addr::from(report.local_address),
+                Ipv4Addr::from(offender)
+            );
+            return Ok(());
+        }
+        let endpoints = self
+            .endpoints
+            .read()
+            .map_err(|_| anyhow::anyhow!("Failed to read lock"))?;
+        let Some(handler) = endpoints
+            .get(&(protocol, report.local_address, local_port))
+            .or_else(|| endpoints.get(&(protocol, IP_ADDRESS_ANY, local_port)))
+        else {
+            debug!(
+                "no endpoint, protocol={:?}, local={}:{}, err={}",
+                protocol,
+                Ipv4Addr::from(report.local_address),
+                local_port,
+                error
+            );
+            return Ok(());
+        };
+        self.statistics
+            .errors_delivered
+            .fetch_add(1, Ordering::Relaxed);
+        debug!(
+            "error delivered, protocol={:?}, local={}:{}, err={}",
+            protocol,
+            Ipv4Addr::from(report.local_address),
+            local_port,
+            error
+        );
+        handler(context, &report)
+    }
+
+    /// Handles a message received in `packet`, answering Echo Requests addressed
+    /// to one of our unicast addresses and delivering errors to the endpoints.
+    pub(crate) fn input(
+        &self,
+        context: &NetDeviceContext,
+        device_index: u32,
+        packet: &IPPacket,
+    ) -> Result<()> {
+        self.statistics.received.fetch_add(1, Ordering::Relaxed);
+        let message = match ICMPMessage::parse(packet.data()) {
+            Ok(message) => message,
+            Err(e) => {
+                self.statistics.errors.fetch_add(1, Ordering::Relaxed);
+                debug!("dropped, dev={}, err={}", device_index, e);
+                return Ok(());
+            }
+        };
+        let header = packet.header();
+        debug!(
+            "input, dev={}, src={}, type={}, code={}, len={}",
+            device_index,
+            Ipv4Addr::from(header.source_ip_address()),
+            message.icmp_type,
+            message.code,
+            message.data.len()
+        );
+        match message.icmp_type {
+            ICMP_TYPE_ECHO_REQUEST => {
+                self.statistics
+                    .echo_requests
+                    .fetch_add(1, Ordering::Relaxed);
+                if context
+                    .lookup_ip_interface(header.destination_ip_address())?
+                    .is_none()
+                {
+                    debug!(
+                        "echo request to a broadcast ignored, dst={}",
+                        Ipv4Addr::from(header.destination_ip_address())
+                    );
+                    return Ok(());
+                }
+                if !self.rate_limit_allows(context, ICMP_TYPE_ECHO_REPLY)? {
+                    return Ok(());
+                }
+                let reply =
+                    ICMPMessage::echo_reply(message.identifier(), message.sequence(), message.data);
+                self.statistics.echo_replies.fetch_add(1, Ordering::Relaxed);
+                self.output(
+                    context,
+                    reply,
+                    header.destination_ip_address(),
+                    header.source_ip_address(),
+                )
+            }
+            ICMP_TYPE_ECHO_REPLY => {
+                let echo_handlers = self
+                    .echo_handlers
+                    .read()
+                    .map_err(|_| anyhow::anyhow!("Failed to read lock"))?;
+                match echo_handlers.get(&message.identifier()) {
+                    Some(handler) => handler(context, packet, &message),
+                    None => {
+                        debug!("no echo handler, identifier={}", message.identifier());
+                        Ok(())
+                    }
+                }
+            }
+            ICMP_TYPE_DEST_UNREACHABLE | ICMP_TYPE_TIME_EXCEEDED | ICMP_TYPE_PARAMETER_PROBLEM => {
+                self.deliver_error(context, header.source_ip_address(), &message)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Sends `message` from `source` to `destination`.
+    pub fn output(
+        &self,
+        context: &NetDeviceContext,
+        message: ICMPMessage,
+        source: u32,
+        destination: u32,
+    ) -> Result<()> {
+        debug!(
+            "output, dst={}, type={}, code={}, len={}",
+            Ipv4Addr::from(destination),
+            message.icmp_type,
+            message.code,
+            message.data.len()
+        );
+        context.ip_controller().output(
+            context,
+            IPProtocol::ICMP,
+            message.serialize(),
+            source,
+            destination,
+        )?;
+        self.statistics.sent.fetch_add(1, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Sends a Destination Unreachable message of `code` about `packet` back to
+    /// its source, e.g. `ICMP_CODE_PORT_UNREACHABLE` from an upper-layer handler.
+    pub fn destination_unreachable(
+        &self,
+        context: &NetDeviceContext,
+        code: u8,
+        packet: &IPPacket,
+    ) -> Result<()> {
+        self.error(
+            context,
+            ICMP_TYPE_DEST_UNREACHABLE,
+            code,
+            0,
+            &packet.serialize(),
+        )
+    }
+
+    /// Reports that `packet` could not reach its next hop, e.g. after a failed ARP
+    /// resolution, with a Host Unreachable. The error is handed straight to our
+    /// endpoints when we sent the packet, and sent back to its source otherwise.
+    pub fn host_unreachable(&self, context: &NetDeviceContext, packet: &IPPacket) -> Result<()> {
+        let header = packet.header();
+        if context
+            .lookup_ip_interface(header.source_ip_address())?
+            .is_none()
+        {
+            return self.destination_unreachable(context, ICMP_CODE_HOST_UNREACHABLE, packet);
+        }
+        let is_icmp_error = header.protocol() == IPProtocol::ICMP
+            && packet
+                .data()
+                .first()
+                .is_none_or(|&icmp_type| ICMPMessage::is_error_type(icmp_type));
+        if header.fragment_offset() != 0 || is_icmp_error {
+            return Ok(());
+        }
+        let message = ICMPMessage::error(
+            ICMP_TYPE_DEST_UNREACHABLE,
+            ICMP_CODE_HOST_UNREACHABLE,
+            0,
+            &packet.serialize(),
+        );
+        self.deliver_error(context, header.source_ip_address(), &message)
+    }
+
+    /// Sends a Fragmentation Needed message about `packet`, advertising the `mtu`
+    /// of the next hop (RFC 1191).
+    pub fn fragmentation_needed(
+        &self,
+        context: &NetDeviceContext,
+        mtu: u16,
+        packet: &IPPacket,
+    ) -> Result<()> {
+        self.error(
+            context,
+            ICMP_TYPE_DEST_UNREACHABLE,
+            ICMP_CODE_FRAGMENTATION_NEEDED,
+            mtu as u32,
+            &packet.serialize(),
+        )
+    }
+
+    /// Sends a Time Exceeded message of `code` about `packet` back to its source.
+    pub fn time_exceeded(
+        &self,
+        context: &NetDeviceContext,
+        code: u8,
+        packet: &IPPacket,
+    ) -> Result<()> {
+        self.error(
+            context,
+            ICMP_TYPE_TIME_EXCEEDED,
+            code,
+            0,
+            &packet.serialize(),
+        )
+    }
+
+    /// Sends a Parameter Problem message about the raw `datagram`, `pointer` being
+    /// the offset of the offending byte from the start of its header.
+    pub fn parameter_problem(
+        &self,
+        context: &NetDeviceContext,
+        pointer: u8,
+        datagram: &[u8],
+    ) -> Result<()> {
+        self.error(
+            context,
+            ICMP_TYPE_PARAMETER_PROBLEM,
+            0,
+            (pointer as u32) << 24,
+            datagram,
+        )
+    }
+
+    /// Sends an error message about the raw `datagram` back to its source, unless
+    /// RFC 1122 forbids it: no error about an ICMP error, a datagram sent to or
+    /// from a broadcast or multicast address, or a fragment other than the first.
+    fn error(
+        &self,
+        context: &NetDeviceContext,
+        icmp_type: u8,
+        code: u8,
+        rest: u32,
+        datagram: &[u8],
+    ) -> Result<()> {
+        if datagram.len() < IP_HEADER_LENGTH_MIN {
+            return Err(anyhow::anyhow!(
+                "too short datagram, len={}",
+                datagram.len()
+            ));
+        }
+        let header_length = (datagram[0] & 0x0f) as usize * 4;
+        let fragment_offset = u16::from_be_bytes([datagram[6] & 0x1f, datagram[7]]);
+        let protocol = IPProtocol::from(datagram[9]);
+        let source = u32::from_be_bytes([datagram[12], datagram[13], datagram[14], datagram[15]]);
+        let destination =
+            u32::from_be_bytes([datagram[16], datagram[17], datagram[18], datagram[19]]);
+        let interfaces = context.all_ip_interfaces()?;
+        let is_broadcast = |address: u32| {
+            address == IP_ADDRESS_ANY
+                || address == IP_ADDRESS_BROADCAST
+                || Ipv4Addr::from(address).is_multicast()
+                || interfaces
+                    .iter()
+                    .any(|(_, interface)| interface.broadcast == address)
+        };
+        let reason = if fragment_offset != 0 {
+            Some("non-first fragment")
+        } else if is_broadcast(destination) || is_broadcast(source) {
+            Some("broadcast")
+        } else if protocol == IPProtocol::ICMP
+            && datagram
+                .get(header_length)
+                .is_none_or(|&icmp_type| ICMPMessage::is_error_type(icmp_type))
+        {
+            Some("icmp error")
+        } else {
+            None
+        };
+        if let Some(reason) = reason {
+            self.statistics
+                .errors_suppressed
+                .fetch_add(1, Ordering::Relaxed);
+            debug!(
+                "error suppressed, type={}, code={}, src={}, dst={}, reason={}",
+                icmp_type,
+                code,
+                Ipv4Addr::from(source),
+                Ipv4Addr::from(destination),
+                reason
+            );
+            return Ok(());
+        }
+        // Fragmentation Needed is left out of the Destination Unreachable limit, so
+        // that a flood of other unreachables cannot starve path MTU discovery.
+        let limited =
+            !(icmp_type == ICMP_TYPE_DEST_UNREACHABLE && code == ICMP_CODE_FRAGMENTATION_NEEDED);
+        if limited && !self.rate_limit_allows(context, icmp_type)? {
+            return Ok(());
+        }
+        let local = interfaces
+            .iter()
+            .any(|(_, interface)| interface.unicast == destination);
+        let message = ICMPMessage::error(icmp_type, code, rest, datagram);
+        self.output(
+            context,
+            message,
+            if local { destination } else { IP_ADDRESS_ANY },
+            source,
+        )
+    }
+
+    fn rate_limit_allows(&self, context: &NetDeviceContext, icmp_type: u8) -> Result<bool> {
+        if context.ip_controller().icmp_rate_limit_allows(icmp_type)? {
+            return Ok(true);
+        }
+        self.statistics.rate_limited.fetch_add(1, Ordering::Relaxed);
+        debug!("rate limited, type={}", icmp_type);
+        Ok(false)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc,
+    };
+
+    use super::*;
+    use crate::ip::IPRateLimit;
+
+    /// A Port Unreachable from 192.0.2.9 to 192.0.2.1 about a UDP datagram from
+    /// `source`, port 5000.
+    fn port_unreachable(source: u32) -> Vec<u8> {
+        let quoted = IPPacket::new(
+            IPProtocol::UDP,
+            source,
+            0xc6336401,
+            1,
+            64,
+            vec![0x13, 0x88, 0x82, 0x9a, 0, 8, 0, 0],
+        );
+        let message = ICMPMessage::error(
+            ICMP_TYPE_DEST_UNREACHABLE,
+            ICMP_CODE_PORT_UNREACHABLE,
+            0,
+            &quoted.serialize(),
+        );
+        IPPacket::new(
+            IPProtocol::ICMP,
+            0xc0000209,
+            0xc0000201,
+            2,
+            64,
+            message.serialize(),
+        )
+        .serialize()
+    }
+
+    #[test]
+    fn errors_are_delivered_only_for_local_sources() {
+        let (context, _) = crate::net::tests::context(&["192.0.2.1/24"]);
+        let delivered = Arc::new(AtomicU32::new(0));
+        let counter = delivered.clone();
+        context
+            .icmp_controller()
+            .register_endpoint(
+                IPProtocol::UDP,
+                IP_ADDRESS_ANY,
+                5000,
+                Box::new(move |_, _| {
+                    counter.fetch_add(1, Ordering::Relaxed);
+                    Ok(())
+                }),
+            )
+            .unwrap();
+        context
+            .ip_controller()
+            .input(&context, 0, port_unreachable(0xcb007107))
+            .unwrap();
+        assert_eq!(delivered.load(Ordering::Relaxed), 0);
+        context
+            .ip_controller()
+            .input(&context, 0, port_unreachable(0xc0000201))
+            .unwrap();
+        assert_eq!(delivered.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn fragmentation_needed_is_not_rate_limited() {
+        let (context, sent) = crate::net::tests::context(&["192.0.2.1/24"]);
+        context
+            .ip_controller()
+            .set_icmp_rate_limit(
+                ICMP_TYPE_DEST_UNREACHABLE,
+                Some(IPRateLimit { rate: 1, burst: 1 }),
+            )
+            .unwrap();
+        let packet = IPPacket::new(IPProtocol::UDP, 0xc0000209, 0xc0000201, 1, 64, vec![0; 8]);
+        let icmp = context.icmp_controller();
+        for _ in 0..3 {
+            icmp.destination_unreachable(&context, ICMP_CODE_PORT_UNREACHABLE, &packet)
+                .unwrap();
+        }
+        assert_eq!(sent[0].lock().unwrap().len(), 1);
+        for _ in 0..3 {
+            icmp.fragmentation_needed(&context, 1280, &packet).unwrap();
+        }
+        assert_eq!(sent[0].lock().unwrap().len(), 4);
+    }
+}
@@ -1,26 +1,195 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    net::Ipv4Addr,
+    sync::{
+        atomic::{AtomicBool, AtomicU16, AtomicU64, Ordering},
+        Mutex, RwLock,
+    },
+    time::{Duration, Instant},
+};
+
 use anyhow::Result;
+use log::{debug, info};
+
+use crate::icmp::{
+    ICMP_CODE_NET_UNREACHABLE, ICMP_CODE_PROTOCOL_UNREACHABLE, ICMP_CODE_REASSEMBLY_TIME_EXCEEDED,
+    ICMP_CODE_TTL_EXCEEDED, ICMP_TYPE_DEST_UNREACHABLE, ICMP_TYPE_ECHO_REPLY,
+    ICMP_TYPE_PARAMETER_PROBLEM, ICMP_TYPE_TIME_EXCEEDED,
+};
+use crate::net::NetDeviceContext;
 
 pub const IP_ADDRESS_LENGTH: u8 = 4;
+pub const IP_HEADER_LENGTH_MIN: usize = 20;
+pub const IP_HEADER_LENGTH_MAX: usize = 60;
+pub const IP_ADDRESS_ANY: u32 = 0x00000000;
+pub const IP_ADDRESS_BROADCAST: u32 = 0xffffffff;
+pub const IP_TTL_DEFAULT: u8 = 64;
+const IP_IDENTIFICATION_INITIAL: u16 = 128;
+/// Time allowed for all the fragments of a datagram to arrive.
+const IP_REASSEMBLY_TIMEOUT: Duration = Duration::from_secs(30);
+/// Bytes of fragment data held across all the datagrams being reassembled.
+const IP_REASSEMBLY_MEMORY_MAX: usize = 256 * 1024;
+pub(crate) const IP_REASSEMBLY_TIMER_INTERVAL: Duration = Duration::from_secs(1);
+/// Default limit of the outbound ICMP errors and echo replies, per message type.
+pub const IP_ICMP_RATE_LIMIT_DEFAULT: IPRateLimit = IPRateLimit {
+    rate: 100,
+    burst: 50,
+};
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq, Eq)]
+pub enum IPError {
+    /// The buffer is shorter than the part of the packet being read.
+    TooShort { len: usize },
+    /// The version field is not 4.
+    InvalidVersion(u8),
+    /// IHL is below 5 or points past the end of the buffer.
+    InvalidHeaderLength(u8),
+    /// Total length is shorter than the header or longer than the buffer.
+    InvalidTotalLength { total_length: u16, len: usize },
+    /// The header checksum does not verify, `checksum` is the value carried by the packet.
+    InvalidChecksum { checksum: u16 },
+    /// An option is truncated or has a length or pointer invalid for its type.
+    /// `offset` is the position of the option from the start of the header.
+    InvalidOption { option_type: u8, offset: usize },
+    /// No route matches the destination.
+    NoRoute(u32),
+    /// The datagram exceeds the MTU of the outgoing device but fragmentation is
+    /// forbidden by DF, or the MTU cannot fit any fragment.
+    FragmentationNeeded { mtu: u16 },
+}
+impl fmt::Display for IPError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IPError::TooShort { len } => write!(f, "too short packet, len={}", len),
+            IPError::InvalidVersion(version) => {
+                write!(f, "invalid ip version, version={}", version)
+            }
+            IPError::InvalidHeaderLength(ihl) => write!(f, "invalid header length, ihl={}", ihl),
+            IPError::InvalidTotalLength { total_length, len } => write!(
+                f,
+                "invalid total length, total_length={}, len={}",
+                total_length, len
+            ),
+            IPError::InvalidChecksum { checksum } => {
+                write!(f, "invalid checksum, checksum=0x{:04x}", checksum)
+            }
+            IPError::InvalidOption {
+                option_type,
+                offset,
+            } => write!(f, "invalid option, type={}, offset={}", option_type, offset),
+            IPError::NoRoute(destination) => {
+                write!(f, "no route, dst={}", Ipv4Addr::from(*destination))
+            }
+            IPError::FragmentationNeeded { mtu } => {
+                write!(f, "fragmentation needed, mtu={}", mtu)
+            }
+        }
+    }
+}
+impl std::error::Error for IPError {}
+
+/// Counters of the IP layer.
+#[derive(Debug, Default)]
+pub struct IPStatistics {
+    pub received: AtomicU64,
+    pub header_errors: AtomicU64,
+    pub checksum_errors: AtomicU64,
+    /// Datagrams dropped because they were not addressed to the receiving interface.
+    pub address_errors: AtomicU64,
+    pub unknown_protocols: AtomicU64,
+    pub delivered: AtomicU64,
+    pub sent: AtomicU64,
+    /// Datagrams sent on to another host, when forwarding is enabled.
+    pub forwarded: AtomicU64,
+    /// Datagrams dropped while forwarding because no route matched.
+    pub no_routes: AtomicU64,
+    /// Datagrams dropped because their TTL expired in transit.
+    pub ttl_exceeded: AtomicU64,
+    /// Datagrams split into fragments on output.
+    pub fragmented: AtomicU64,
+    pub fragments_created: AtomicU64,
+    /// Datagrams dropped on output because they needed fragmentation but had DF set.
+    pub fragmentation_failures: AtomicU64,
+    /// Fragments received for reassembly.
+    pub reassembly_required: AtomicU64,
+    pub reassembled: AtomicU64,
+    /// Datagrams given up on, because of a timeout, inconsistent fragments or the
+    /// memory cap.
+    pub reassembly_failures: AtomicU64,
+}
+impl IPStatistics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+/// Computes the Internet checksum (RFC 1071) of `data`.
+///
+/// `init` is added to the sum, which allows to chain a pseudo-header: pass the
+/// one's complement of the checksum of the pseudo-header (`!checksum(pseudo, 0)`).
+/// Verifying data including its checksum field yields 0.
+pub fn checksum(data: &[u8], init: u32) -> u16 {
+    let mut sum = init;
+    let mut chunks = data.chunks_exact(2);
+    for chunk in &mut chunks {
+        sum += u16::from_be_bytes([chunk[0], chunk[1]]) as u32;
+        if sum > 0xffff {
+            sum = (sum & 0xffff) + (sum >> 16);
+        }
+    }
+    if let [last] = chunks.remainder() {
+        sum += u16::from_be_bytes([*last, 0]) as u32;
+    }
+    while sum >> 16 != 0 {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+#[derive(Debug, Clone)]
 enum IPVersion {
     IPv4,
-    IPv6,
 }
-#[derive(Debug)]
-enum IPProtocol {
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[allow(clippy::upper_case_acronyms)]
+pub enum IPProtocol {
     ICMP,
     TCP,
     UDP,
+    /// Any other protocol number, e.g. 2 for IGMP or 47 for GRE.
+    Other(u8),
 }
 
-#[derive(Debug)]
+impl From<u8> for IPProtocol {
+    fn from(protocol: u8) -> Self {
+        match protocol {
+            1 => IPProtocol::ICMP,
+            6 => IPProtocol::TCP,
+            17 => IPProtocol::UDP,
+            protocol => IPProtocol::Other(protocol),
+        }
+    }
+}
+
+impl From<IPProtocol> for u8 {
+    fn from(protocol: IPProtocol) -> Self {
+        match protocol {
+            IPProtocol::ICMP => 1,
+            IPProtocol::TCP => 6,
+            IPProtocol::UDP => 17,
+            IPProtocol::Other(protocol) => protocol,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
 pub struct IPPacket {
     header: IPHeader,
     data: Vec<u8>,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct IPHeader {
     version: IPVersion,
     ihl: u8,
@@ -38,37 +207,54 @@ pub struct IPHeader {
     header_checksum: u16,
     source_ip_address: u32,
     destination_ip_address: u32,
-    options: Vec<u8>,
+    options: Vec<IPOption>,
+    /// Bytes of the options area past the End option, kept so that a parsed
+    /// header serializes back as received.
+    padding: Vec<u8>,
 }
 
 impl IPHeader {
-    fn parse(data: &[u8]) -> Result<Self> {
+    fn parse(data: &[u8]) -> Result<Self, IPError> {
+        if data.len() < IP_HEADER_LENGTH_MIN {
+            return Err(IPError::TooShort { len: data.len() });
+        }
         let version = match data[0] >> 4 {
             4 => IPVersion::IPv4,
-            6 => IPVersion::IPv6,
-            _ => return Err(anyhow::anyhow!("Invalid IP version")),
+            version => return Err(IPError::InvalidVersion(version)),
         };
         let ihl = data[0] & 0x0F;
+        let header_length = ihl as usize * 4;
+        if header_length < IP_HEADER_LENGTH_MIN || header_length > data.len() {
+            return Err(IPError::InvalidHeaderLength(ihl));
+        }
+        if checksum(&data[..header_length], 0) != 0 {
+            return Err(IPError::InvalidChecksum {
+                checksum: u16::from_be_bytes([data[10], data[11]]),
+            });
+        }
         let precedence = data[1] >> 5;
         let delay = (data[1] >> 4) & 1 == 1;
         let throughput = (data[1] >> 3) & 1 == 1;
         let reliability = (data[1] >> 2) & 1 == 1;
         let total_length = u16::from_be_bytes([data[2], data[3]]);
+        if (total_length as usize) < header_length || total_length as usize > data.len() {
+            return Err(IPError::InvalidTotalLength {
+                total_length,
+                len: data.len(),
+            });
+        }
         let identification = u16::from_be_bytes([data[4], data[5]]);
         let df = (data[6] >> 6) & 1 == 1;
         let mf = (data[6] >> 5) & 1 == 1;
         let fragment_offset = u16::from_be_bytes([data[6] & 0x1F, data[7]]);
         let ttl = data[8];
-        let protocol = match data[9] {
-            1 => IPProtocol::ICMP,
-            6 => IPProtocol::TCP,
-            17 => IPProtocol::UDP,
-            _ => return Err(anyhow::anyhow!("Invalid IP protocol")),
-        };
+        let protocol = IPProtocol::from(data[9]);
         let header_checksum = u16::from_be_bytes([data[10], data[11]]);
         let source_ip_address = u32::from_be_bytes([data[12], data[13], data[14], data[15]]);
         let destination_ip_address = u32::from_be_bytes([data[16], data[17], data[18], data[19]]);
-        let options = data[20..(ihl as usize * 4)].to_vec();
+        let area = &data[IP_HEADER_LENGTH_MIN..header_length];
+        let options = IPOption::parse_list(area)?;
+        let padding = area[IPOption::serialize_list(&options).len()..].to_vec();
         Ok(IPHeader {
             version,
             ihl,
@@ -87,27 +273,1929 @@ impl IPHeader {
             source_ip_address,
             destination_ip_address,
             options,
+            padding,
         })
     }
+    pub fn total_length(&self) -> u16 {
+        self.total_length
+    }
+    pub fn identification(&self) -> u16 {
+        self.identification
+    }
+    pub fn df(&self) -> bool {
+        self.df
+    }
+    pub fn mf(&self) -> bool {
+        self.mf
+    }
+    pub fn fragment_offset(&self) -> u16 {
+        self.fragment_offset
+    }
+    pub fn ttl(&self) -> u8 {
+        self.ttl
+    }
+    pub fn protocol(&self) -> IPProtocol {
+        self.protocol
+    }
+    pub fn header_checksum(&self) -> u16 {
+        self.header_checksum
+    }
+    pub fn source_ip_address(&self) -> u32 {
+        self.source_ip_address
+    }
+    pub fn destination_ip_address(&self) -> u32 {
+        self.destination_ip_address
+    }
+    pub fn options(&self) -> &[IPOption] {
+        &self.options
+    }
     fn data_offset(&self) -> usize {
         (self.ihl << 2) as usize
     }
+    /// The options area: the options, the padding kept from parsing, then End
+    /// up to a multiple of 4 bytes.
+    fn serialize_options(&self) -> Vec<u8> {
+        let mut options = IPOption::serialize_list(&self.options);
+        options.extend_from_slice(&self.padding);
+        options.resize(options.len().div_ceil(4) * 4, IP_OPTION_TYPE_END);
+        options
+    }
+    /// Serializes the header of a datagram carrying `data_length` bytes, computing
+    /// `ihl` and the total length from the options, and the checksum.
+    pub(crate) fn serialize(&self, data_length: usize) -> Vec<u8> {
+        let options = self.serialize_options();
+        let header_length = IP_HEADER_LENGTH_MIN + options.len();
+        let total_length = (header_length + data_length) as u16;
+        let mut data = Vec::with_capacity(header_length);
+        let version = match self.version {
+            IPVersion::IPv4 => 4,
+        };
+        data.push(version << 4 | (header_length / 4) as u8);
+        data.push(
+            self.precedence << 5
+                | (self.delay as u8) << 4
+                | (self.throughput as u8) << 3
+                | (self.reliability as u8) << 2,
+        );
+        data.extend_from_slice(&total_length.to_be_bytes());
+        data.extend_from_slice(&self.identification.to_be_bytes());
+        let flags_and_offset =
+            (self.df as u16) << 14 | (self.mf as u16) << 13 | self.fragment_offset;
+        data.extend_from_slice(&flags_and_offset.to_be_bytes());
+        data.push(self.ttl);
+        data.push(u8::from(self.protocol));
+        data.extend_from_slice(&[0, 0]);
+        data.extend_from_slice(&self.source_ip_address.to_be_bytes());
+        data.extend_from_slice(&self.destination_ip_address.to_be_bytes());
+        data.extend_from_slice(&options);
+        let sum = checksum(&data, 0);
+        data[10..12].copy_from_slice(&sum.to_be_bytes());
+        data
+    }
 }
 
 impl IPPacket {
-    pub fn parse(data: Vec<u8>) -> Result<Self> {
-        let header = IPHeader::parse(&data)?;
-        let data = data[header.data_offset()..].to_vec();
+    pub fn new(
+        protocol: IPProtocol,
+        source_ip_address: u32,
+        destination_ip_address: u32,
+        identification: u16,
+        ttl: u8,
+        data: Vec<u8>,
+    ) -> Self {
+        IPPacket {
+            header: IPHeader {
+                version: IPVersion::IPv4,
+                ihl: (IP_HEADER_LENGTH_MIN / 4) as u8,
+                precedence: 0,
+                delay: false,
+                throughput: false,
+                reliability: false,
+                total_length: (IP_HEADER_LENGTH_MIN + data.len()) as u16,
+                identification,
+                df: false,
+                mf: false,
+                fragment_offset: 0,
+                ttl,
+                protocol,
+                header_checksum: 0,
+                source_ip_address,
+                destination_ip_address,
+                options: Vec::new(),
+                padding: Vec::new(),
+            },
+            data,
+        }
+    }
+    /// Parses a received packet. Bytes past the total length (e.g. link-layer
+    /// padding) are discarded.
+    pub fn parse(data: &[u8]) -> Result<Self, IPError> {
+        let header = IPHeader::parse(data)?;
+        let data = data[header.data_offset()..header.total_length as usize].to_vec();
         Ok(IPPacket { header, data })
     }
+    pub fn header(&self) -> &IPHeader {
+        &self.header
+    }
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut data = self.header.serialize(self.data.len());
+        data.extend_from_slice(&self.data);
+        data
+    }
+    /// Splits the packet into fragments fitting in `mtu` (RFC 791).
+    ///
+    /// Data is cut at multiples of 8 bytes. The first fragment carries all the
+    /// options, the others only the ones with the copied flag. A packet that already
+    /// fits is returned as is, and fragmenting a fragment keeps its offset and `mf`.
+    pub fn fragment(&self, mtu: u16) -> Result<Vec<IPPacket>, IPError> {
+        if self.header.total_length <= mtu {
+            return Ok(vec![self.clone()]);
+        }
+        if self.header.df {
+            return Err(IPError::FragmentationNeeded { mtu });
+        }
+        let copied_options: Vec<IPOption> = self
+            .header
+            .options
+            .iter()
+            .filter(|option| option.copied())
+            .cloned()
+            .collect();
+        let mut fragments = Vec::new();
+        let mut offset = 0;
+        while offset < self.data.len() {
+            let mut header = self.header.clone();
+            if offset > 0 {
+                header.options = copied_options.clone();
+                header.padding = Vec::new();
+            }
+            let header_length = IP_HEADER_LENGTH_MIN + header.serialize_options().len();
+            let max_length = (mtu as usize).saturating_sub(header_length) & !7;
+            if max_length == 0 {
+                return Err(IPError::FragmentationNeeded { mtu });
+            }
+            let length = max_length.min(self.data.len() - offset);
+            header.ihl = (header_length / 4) as u8;
+            header.total_length = (header_length + length) as u16;
+            header.mf = offset + length < self.data.len() || self.header.mf;
+            header.fragment_offset = self.header.fragment_offset + (offset / 8) as u16;
+            fragments.push(IPPacket {
+                header,
+                data: self.data[offset..offset + length].to_vec(),
+            });
+            offset += length;
+        }
+        Ok(fragments)
+    }
+}
+
+pub const IP_OPTION_TYPE_END: u8 = 0;
+pub const IP_OPTION_TYPE_NOP: u8 = 1;
+pub const IP_OPTION_TYPE_RECORD_ROUTE: u8 = 7;
+pub const IP_OPTION_TYPE_TIMESTAMP: u8 = 68;
+pub const IP_OPTION_TYPE_LOOSE_SOURCE_ROUTE: u8 = 131;
+pub const IP_OPTION_TYPE_STRICT_SOURCE_ROUTE: u8 = 137;
+pub const IP_OPTION_TYPE_ROUTER_ALERT: u8 = 148;
+/// Flag of the Timestamp option: timestamps only.
+pub const IP_OPTION_TIMESTAMP_ONLY: u8 = 0;
+/// Flag of the Timestamp option: each timestamp preceded by the address of its host.
+pub const IP_OPTION_TIMESTAMP_WITH_ADDRESS: u8 = 1;
+/// Flag of the Timestamp option: timestamps of the prespecified addresses.
+pub const IP_OPTION_TIMESTAMP_PRESPECIFIED: u8 = 3;
+
+/// An option of the IPv4 header (RFC 791, RFC 2113 for Router Alert).
+///
+/// `pointer` is the raw field of the option, i.e. the 1-based offset of the next
+/// free slot from the start of the option.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum IPOption {
+    End,
+    NoOperation,
+    RecordRoute {
+        pointer: u8,
+        route: Vec<u32>,
+    },
+    Timestamp {
+        pointer: u8,
+        overflow: u8,
+        flag: u8,
+        /// Timestamps, or address and timestamp pairs depending on `flag`.
+        data: Vec<u32>,
+    },
+    LooseSourceRoute {
+        pointer: u8,
+        route: Vec<u32>,
+    },
+    StrictSourceRoute {
+        pointer: u8,
+        route: Vec<u32>,
+    },
+    RouterAlert(u16),
+    /// An option of another type, kept as is.
+    Unknown {
+        option_type: u8,
+        data: Vec<u8>,
+    },
+}
+
+impl IPOption {
+    /// Builds an empty Record Route option with room for `slots` addresses.
+    pub fn record_route(slots: usize) -> Self {
+        IPOption::RecordRoute {
+            pointer: 4,
+            route: vec![IP_ADDRESS_ANY; slots],
+        }
+    }
+
+    /// Builds an empty Timestamp option of `flag` with room for `slots` entries.
+    pub fn timestamp(flag: u8, slots: usize) -> Self {
+        let words = if flag == IP_OPTION_TIMESTAMP_ONLY {
+            slots
+        } else {
+            slots * 2
+        };
+        IPOption::Timestamp {
+            pointer: 5,
+            overflow: 0,
+            flag,
+            data: vec![0; words],
+        }
+    }
+
+    pub fn option_type(&self) -> u8 {
+        match self {
+            IPOption::End => IP_OPTION_TYPE_END,
+            IPOption::NoOperation => IP_OPTION_TYPE_NOP,
+            IPOption::RecordRoute { .. } => IP_OPTION_TYPE_RECORD_ROUTE,
+            IPOption::Timestamp { .. } => IP_OPTION_TYPE_TIMESTAMP,
+            IPOption::LooseSourceRoute { .. } => IP_OPTION_TYPE_LOOSE_SOURCE_ROUTE,
+            IPOption::StrictSourceRoute { .. } => IP_OPTION_TYPE_STRICT_SOURCE_ROUTE,
+            IPOption::RouterAlert(_) => IP_OPTION_TYPE_ROUTER_ALERT,
+            IPOption::Unknown { option_type, .. } => *option_type,
+        }
+    }
+
+    /// Whether the option has to be repeated in every fragment (the copied flag,
+    /// high bit of the type).
+    pub fn copied(&self) -> bool {
+        self.option_type() & 0x80 != 0
+    }
+
+    /// Parses the options area of a header. Parsing stops at the End option, the
+    /// rest being padding.
+    pub fn parse_list(data: &[u8]) -> Result<Vec<IPOption>, IPError> {
+        let mut options = Vec::new();
+        let mut offset = 0;
+        while offset < data.len() {
+            let option_type = data[offset];
+            let invalid = IPError::InvalidOption {
+                option_type,
+                offset: IP_HEADER_LENGTH_MIN + offset,
+            };
+            let length = match option_type {
+                IP_OPTION_TYPE_END => {
+                    options.push(IPOption::End);
+                    break;
+                }
+                IP_OPTION_TYPE_NOP => 1,
+                _ => match data.get(offset + 1) {
+                    Some(&length) if length >= 2 && offset + length as usize <= data.len() => {
+                        length as usize
+                    }
+                    _ => return Err(invalid),
+                },
+            };
+            let option = Self::parse(&data[offset..offset + length]).ok_or(invalid)?;
+            options.push(option);
+            offset += length;
+        }
+        Ok(options)
+    }
+
+    /// Parses a single option whose length was checked against the buffer.
+    fn parse(data: &[u8]) -> Option<IPOption> {
+        let words = |data: &[u8]| -> Vec<u32> {
+            data.chunks_exact(4)
+                .map(|chunk| u32::from_be_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]))
+                .collect()
+        };
+        let route = |data: &[u8]| -> Option<(u8, Vec<u32>)> {
+            let pointer = *data.get(2)?;
+            if !(data.len() - 3).is_multiple_of(4) || pointer < 4 {
+                return None;
+            }
+            Some((pointer, words(&data[3..])))
+        };
+        let option = match data[0] {
+            IP_OPTION_TYPE_END => IPOption::End,
+            IP_OPTION_TYPE_NOP => IPOption::NoOperation,
+            IP_OPTION_TYPE_RECORD_ROUTE => {
+                let (pointer, route) = route(data)?;
+                IPOption::RecordRoute { pointer, route }
+            }
+            IP_OPTION_TYPE_LOOSE_SOURCE_ROUTE => {
+                let (pointer, route) = route(data)?;
+                IPOption::LooseSourceRoute { pointer, route }
+            }
+            IP_OPTION_TYPE_STRICT_SOURCE_ROUTE => {
+                let (pointer, route) = route(data)?;
+                IPOption::StrictSourceRoute { pointer, route }
+            }
+            IP_OPTION_TYPE_TIMESTAMP => {
+                if data.len() < 4 {
+                    return None;
+                }
+                let pointer = data[2];
+                let overflow = data[3] >> 4;
+                let flag = data[3] & 0x0f;
+                let entry_length = match flag {
+                    IP_OPTION_TIMESTAMP_ONLY => 4,
+                    IP_OPTION_TIMESTAMP_WITH_ADDRESS | IP_OPTION_TIMESTAMP_PRESPECIFIED => 8,
+                    _ => return None,
+                };
+                if !(data.len() - 4).is_multiple_of(entry_length) || pointer < 5 {
+                    return None;
+                }
+                IPOption::Timestamp {
+                    pointer,
+                    overflow,
+                    flag,
+                    data: words(&data[4..]),
+                }
+            }
+            IP_OPTION_TYPE_ROUTER_ALERT => {
+                if data.len() != 4 {
+                    return None;
+                }
+                IPOption::RouterAlert(u16::from_be_bytes([data[2], data[3]]))
+            }
+            option_type => IPOption::Unknown {
+                option_type,
+                data: data[2..].to_vec(),
+            },
+        };
+        Some(option)
+    }
+
+    pub fn serialize(&self) -> Vec<u8> {
+        let route = |option_type: u8, pointer: u8, route: &[u32]| -> Vec<u8> {
+            let mut data = vec![option_type, (3 + route.len() * 4) as u8, pointer];
+            for address in route {
+                data.extend_from_slice(&address.to_be_bytes());
+            }
+            data
+        };
+        match self {
+            IPOption::End => vec![IP_OPTION_TYPE_END],
+            IPOption::NoOperation => vec![IP_OPTION_TYPE_NOP],
+            IPOption::RecordRoute { pointer, route: r } => {
+                route(IP_OPTION_TYPE_RECORD_ROUTE, *pointer, r)
+            }
+            IPOption::LooseSourceRoute { pointer, route: r } => {
+                route(IP_OPTION_TYPE_LOOSE_SOURCE_ROUTE, *pointer, r)
+            }
+            IPOption::StrictSourceRoute { pointer, route: r } => {
+                route(IP_OPTION_TYPE_STRICT_SOURCE_ROUTE, *pointer, r)
+            }
+            IPOption::Timestamp {
+                pointer,
+                overflow,
+                flag,
+                data: words,
+            } => {
+                let mut data = vec![
+                    IP_OPTION_TYPE_TIMESTAMP,
+                    (4 + words.len() * 4) as u8,
+                    *pointer,
+                    overflow << 4 | (flag & 0x0f),
+                ];
+                for word in words {
+                    data.extend_from_slice(&word.to_be_bytes());
+                }
+                data
+            }
+            IPOption::RouterAlert(value) => {
+                let mut data = vec![IP_OPTION_TYPE_ROUTER_ALERT, 4];
+                data.extend_from_slice(&value.to_be_bytes());
+                data
+            }
+            IPOption::Unknown { option_type, data } => {
+                let mut option = vec![*option_type, (2 + data.len()) as u8];
+                option.extend_from_slice(data);
+                option
+            }
+        }
+    }
+
+    pub fn serialize_list(options: &[IPOption]) -> Vec<u8> {
+        options.iter().flat_map(IPOption::serialize).collect()
+    }
+
+    /// Length of `options` in a header, padded to a multiple of 4 bytes.
+    pub fn padded_length(options: &[IPOption]) -> usize {
+        options
+            .iter()
+            .map(|option| option.serialize().len())
+            .sum::<usize>()
+            .div_ceil(4)
+            * 4
+    }
+}
+
+/// Decrements the TTL of the raw `datagram` and updates its header checksum
+/// (RFC 1624), leaving the other bytes untouched.
+fn decrement_ttl(datagram: &mut [u8]) {
+    datagram[8] -= 1;
+    // The TTL is the high byte of its 16-bit word, which thus drops by 0x0100.
+    let sum = !u16::from_be_bytes([datagram[10], datagram[11]]) as u32 + 0xfeff;
+    let sum = (sum & 0xffff) + (sum >> 16);
+    datagram[10..12].copy_from_slice(&(!(sum as u16)).to_be_bytes());
+}
+
+/// Identifies the fragments of one datagram (RFC 791).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct IPReassemblyKey {
+    source_ip_address: u32,
+    destination_ip_address: u32,
+    protocol: IPProtocol,
+    identification: u16,
+}
+
+impl IPReassemblyKey {
+    fn new(header: &IPHeader) -> Self {
+        IPReassemblyKey {
+            source_ip_address: header.source_ip_address,
+            destination_ip_address: header.destination_ip_address,
+            protocol: header.protocol,
+            identification: header.identification,
+        }
+    }
+}
+
+/// A datagram being reassembled from its fragments.
+#[derive(Debug)]
+struct IPReassembly {
+    /// Header of the first fragment, which becomes the header of the datagram.
+    header: Option<IPHeader>,
+    data: Vec<u8>,
+    /// Byte ranges of `data` received so far, sorted and merged.
+    ranges: Vec<(usize, usize)>,
+    /// Length of the data, known once the last fragment arrived.
+    length: Option<usize>,
+    created: Instant,
+}
+
+impl IPReassembly {
+    fn new() -> Self {
+        IPReassembly {
+            header: None,
+            data: Vec::new(),
+            ranges: Vec::new(),
+            length: None,
+            created: Instant::now(),
+        }
+    }
+
+    /// Adds the data of `fragment`. Bytes already received are kept, so overlapping
+    /// fragments cannot rewrite them. Fails on fragments contradicting the ones
+    /// received before.
+    fn insert(&mut self, fragment: &IPPacket) -> Result<()> {
+        let header = &fragment.header;
+        let start = header.fragment_offset as usize * 8;
+        let end = start + fragment.data.len();
+        if header.mf && !fragment.data.len().is_multiple_of(8) {
+            return Err(anyhow::anyhow!(
+                "fragment length not a multiple of 8, len={}",
+                fragment.data.len()
+            ));
+        }
+        if IP_HEADER_LENGTH_MIN + end > u16::MAX as usize {
+            return Err(anyhow::anyhow!("too long datagram, len={}", end));
+        }
+        match self.length {
+            Some(length) if end > length || (!header.mf && end != length) => {
+                return Err(anyhow::anyhow!(
+                    "fragment past the end, end={}, len={}",
+                    end,
+                    length
+                ));
+            }
+            None if !header.mf => {
+                if self.data.len() > end {
+                    return Err(anyhow::anyhow!(
+                        "fragment past the end, end={}, len={}",
+                        self.data.len(),
+                        end
+                    ));
+                }
+                self.length = Some(end);
+            }
+            _ => {}
+        }
+        if start == 0 && self.header.is_none() {
+            self.header = Some(header.clone());
+        }
+        if self.data.len() < end {
+            self.data.resize(end, 0);
+        }
+        let mut cursor = start;
+        for &(received_start, received_end) in &self.ranges {
+            if received_start >= end {
+                break;
+            }
+            if received_end <= cursor {
+                continue;
+            }
+            if received_start > cursor {
+                self.data[cursor..received_start]
+                    .copy_from_slice(&fragment.data[cursor - start..received_start - start]);
+            }
+            cursor = cursor.max(received_end);
+        }
+        if cursor < end {
+            self.data[cursor..end].copy_from_slice(&fragment.data[cursor - start..]);
+        }
+        self.ranges.push((start, end));
+        self.ranges.sort_unstable();
+        let mut merged: Vec<(usize, usize)> = Vec::with_capacity(self.ranges.len());
+        for &(range_start, range_end) in &self.ranges {
+            match merged.last_mut() {
+                Some(last) if range_start <= last.1 => last.1 = last.1.max(range_end),
+                _ => merged.push((range_start, range_end)),
+            }
+        }
+        self.ranges = merged;
+        Ok(())
+    }
+
+    fn is_complete(&self) -> bool {
+        self.header.is_some()
+            && self
+                .length
+                .is_some_and(|length| self.ranges == [(0, length)])
+    }
+
+    /// Builds the first fragment as far as it arrived, to be quoted by an ICMP
+    /// error. `None` if the first fragment is missing.
+    fn first_fragment(&self) -> Option<IPPacket> {
+        let header = self.header.clone()?;
+        let received = self
+            .ranges
+            .first()
+            .filter(|(start, _)| *start == 0)
+            .map_or(0, |(_, end)| *end);
+        Some(IPPacket {
+            header,
+            data: self.data[..received].to_vec(),
+        })
+    }
+
+    /// Builds the datagram, which has to be complete.
+    fn into_packet(self) -> Option<IPPacket> {
+        let mut header = self.header?;
+        header.total_length = (header.data_offset() + self.data.len()) as u16;
+        header.mf = false;
+        header.fragment_offset = 0;
+        Some(IPPacket {
+            header,
+            data: self.data,
+        })
+    }
+}
+
+/// Per-datagram settings of `IPController::output_with`.
+#[derive(Debug, Clone)]
+pub struct IPOutputOptions {
+    pub ttl: u8,
+    /// Don't Fragment: fail with `IPError::FragmentationNeeded` instead of fragmenting.
+    pub df: bool,
+    /// Options of the header, padded with End to a multiple of 4 bytes on output.
+    pub options: Vec<IPOption>,
+}
+
+impl Default for IPOutputOptions {
+    fn default() -> Self {
+        IPOutputOptions {
+            ttl: IP_TTL_DEFAULT,
+            df: false,
+            options: Vec::new(),
+        }
+    }
+}
+
+/// An IPv4 address assigned to a net device.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IPInterface {
+    pub unicast: u32,
+    pub netmask: u32,
+    pub broadcast: u32,
+}
+
+impl IPInterface {
+    /// Builds the interface of `unicast` in the network of `netmask`, with the
+    /// broadcast address of that network.
+    pub fn new(unicast: u32, netmask: u32) -> Self {
+        IPInterface {
+            unicast,
+            netmask,
+            broadcast: (unicast & netmask) | !netmask,
+        }
+    }
+    /// Parses the CIDR notation of `ip addr add`, e.g. `192.0.2.1/24`.
+    pub fn parse(cidr: &str) -> Result<Self> {
+        let (address, prefix_length) = cidr
+            .split_once('/')
+            .ok_or_else(|| anyhow::anyhow!("missing prefix length, cidr={}", cidr))?;
+        let address: Ipv4Addr = address.parse()?;
+        let prefix_length: u32 = prefix_length.parse()?;
+        if prefix_length > 32 {
+            return Err(anyhow::anyhow!("invalid prefix length, cidr={}", cidr));
+        }
+        let netmask = u32::MAX.checked_shl(32 - prefix_length).unwrap_or(0);
+        Ok(Self::new(u32::from(address), netmask))
+    }
+    pub fn network(&self) -> u32 {
+        self.unicast & self.netmask
+    }
+    pub fn prefix_length(&self) -> u32 {
+        self.netmask.leading_ones()
+    }
+    /// Whether `address` is on the network of the interface.
+    pub fn contains(&self, address: u32) -> bool {
+        address & self.netmask == self.network()
+    }
+    /// Whether `other` is on the same network, i.e. needs the same connected route.
+    pub fn same_network(&self, other: &IPInterface) -> bool {
+        self.netmask == other.netmask && self.network() == other.network()
+    }
+}
+
+impl fmt::Display for IPInterface {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}/{} brd {}",
+            Ipv4Addr::from(self.unicast),
+            self.prefix_length(),
+            Ipv4Addr::from(self.broadcast)
+        )
+    }
+}
+
+/// An entry of the routing table.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IPRoute {
+    pub network: u32,
+    pub netmask: u32,
+    /// Next hop of the route, `IP_ADDRESS_ANY` when the network is directly connected.
+    pub gateway: u32,
+    pub device_index: u32,
+    /// Preference among the routes of the same prefix, the lowest wins.
+    pub metric: u32,
+}
+
+impl IPRoute {
+    pub fn prefix_length(&self) -> u32 {
+        self.netmask.leading_ones()
+    }
+    pub fn matches(&self, address: u32) -> bool {
+        address & self.netmask == self.network
+    }
+    /// Address to hand `destination` to on the link.
+    pub fn next_hop(&self, destination: u32) -> u32 {
+        if self.gateway == IP_ADDRESS_ANY {
+            destination
+        } else {
+            self.gateway
+        }
+    }
+}
+
+impl fmt::Display for IPRoute {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.netmask == 0 {
+            write!(f, "default")?;
+        } else {
+            write!(
+                f,
+                "{}/{}",
+                Ipv4Addr::from(self.network),
+                self.prefix_length()
+            )?;
+        }
+        if self.gateway != IP_ADDRESS_ANY {
+            write!(f, " via {}", Ipv4Addr::from(self.gateway))?;
+        }
+        write!(f, " dev {} metric {}", self.device_index, self.metric)
+    }
+}
+
+/// Token bucket holding up to `burst` messages, refilled with `rate` per second.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IPRateLimit {
+    pub rate: u32,
+    pub burst: u32,
+}
+
+#[derive(Debug)]
+struct IPTokenBucket {
+    limit: IPRateLimit,
+    tokens: f64,
+    last: Instant,
+}
+
+impl IPTokenBucket {
+    fn new(limit: IPRateLimit) -> Self {
+        IPTokenBucket {
+            limit,
+            tokens: limit.burst as f64,
+            last: Instant::now(),
+        }
+    }
+
+    /// Takes a token if one is available.
+    fn take(&mut self) -> bool {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.limit.rate as f64).min(self.limit.burst as f64);
+        self.last = now;
+        if self.tokens < 1.0 {
+            return false;
+        }
+        self.tokens -= 1.0;
+        true
+    }
+}
+
+pub type IPProtocolHandler =
+    Box<dyn Fn(&NetDeviceContext, u32, &IPPacket) -> Result<()> + Send + Sync>;
+
+pub struct IPController {
+    identification: AtomicU16,
+    /// Upper-layer handlers keyed by protocol number.
+    protocols: RwLock<HashMap<u8, IPProtocolHandler>>,
+    routes: RwLock<Vec<IPRoute>>,
+    reassemblies: Mutex<HashMap<IPReassemblyKey, IPReassembly>>,
+    forwarding: AtomicBool,
+    /// Limits of the outbound ICMP messages keyed by ICMP type, the types absent
+    /// being unlimited.
+    icmp_rate_limits: Mutex<HashMap<u8, IPTokenBucket>>,
+    statistics: IPStatistics,
+}
+
+impl Default for IPController {
+    fn default() -> Self {
+        IPController {
+            identification: AtomicU16::new(IP_IDENTIFICATION_INITIAL),
+            protocols: RwLock::new(HashMap::new()),
+            routes: RwLock::new(Vec::new()),
+            reassemblies: Mutex::new(HashMap::new()),
+            forwarding: AtomicBool::new(false),
+            icmp_rate_limits: Mutex::new(
+                [
+                    ICMP_TYPE_ECHO_REPLY,
+                    ICMP_TYPE_DEST_UNREACHABLE,
+                    ICMP_TYPE_TIME_EXCEEDED,
+                    ICMP_TYPE_PARAMETER_PROBLEM,
+                ]
+                .into_iter()
+                .map(|icmp_type| (icmp_type, IPTokenBucket::new(IP_ICMP_RATE_LIMIT_DEFAULT)))
+                .collect(),
+            ),
+            statistics: IPStatistics::new(),
+        }
+    }
+}
+
+impl IPController {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn statistics(&self) -> &IPStatistics {
+        &self.statistics
+    }
+
+    /// Enables or disables forwarding of the datagrams not addressed to this host,
+    /// like `net.ipv4.ip_forward`. Disabled by default.
+    pub fn set_forwarding(&self, enabled: bool) {
+        self.forwarding.store(enabled, Ordering::Relaxed);
+        info!("forwarding={}", enabled);
+    }
+
+    pub fn forwarding(&self) -> bool {
+        self.forwarding.load(Ordering::Relaxed)
+    }
+
+    /// Sets the limit of the ICMP errors and echo replies of `icmp_type` we send,
+    /// `None` lifting it. Error and echo reply types start limited to
+    /// `IP_ICMP_RATE_LIMIT_DEFAULT`, except for Fragmentation Needed which is never
+    /// limited.
+    pub fn set_icmp_rate_limit(&self, icmp_type: u8, limit: Option<IPRateLimit>) -> Result<()> {
+        let mut icmp_rate_limits = self
+            .icmp_rate_limits
+            .lock()
+            .map_err(|_| anyhow::anyhow!("Failed to lock"))?;
+        match limit {
+            Some(limit) => {
+                icmp_rate_limits.insert(icmp_type, IPTokenBucket::new(limit));
+            }
+            None => {
+                icmp_rate_limits.remove(&icmp_type);
+            }
+        }
+        info!("icmp rate limit, type={}, limit={:?}", icmp_type, limit);
+        Ok(())
+    }
+
+    pub fn icmp_rate_limit(&self, icmp_type: u8) -> Result<Option<IPRateLimit>> {
+        Ok(self
+            .icmp_rate_limits
+            .lock()
+            .map_err(|_| anyhow::anyhow!("Failed to lock"))?
+            .get(&icmp_type)
+            .map(|bucket| bucket.limit))
+    }
+
+    /// Whether an ICMP message of `icmp_type` may be sent now, consuming a token
+    /// of its limit.
+    pub(crate) fn icmp_rate_limit_allows(&self, icmp_type: u8) -> Result<bool> {
+        Ok(self
+            .icmp_rate_limits
+            .lock()
+            .map_err(|_| anyhow::anyhow!("Failed to lock"))?
+            .get_mut(&icmp_type)
+            .is_none_or(IPTokenBucket::take))
+    }
+
+    /// Adds the route to the network of `interface` through its device.
+    pub(crate) fn add_connected_route(
+        &self,
+        device_index: u32,
+        interface: &IPInterface,
+    ) -> Result<()> {
+        self.insert_route(IPRoute {
+            network: interface.network(),
+            netmask: interface.netmask,
+            gateway: IP_ADDRESS_ANY,
+            device_index,
+            metric: 0,
+        })
+    }
+
+    /// Removes the route added for `interface` by `add_connected_route`. The caller
+    /// keeps it while another address of the device is on the same network.
+    pub(crate) fn remove_connected_route(
+        &self,
+        device_index: u32,
+        interface: &IPInterface,
+    ) -> Result<()> {
+        let mut routes = self
+            .routes
+            .write()
+            .map_err(|_| anyhow::anyhow!("Failed to write lock"))?;
+        routes.retain(|route| {
+            !(route.device_index == device_index
+                && route.network == interface.network()
+                && route.netmask == interface.netmask
+                && route.gateway == IP_ADDRESS_ANY
+                && route.metric == 0)
+        });
+        Ok(())
+    }
+
+    /// Adds a static route to `network`/`netmask` via `gateway`, like `ip route add`.
+    ///
+    /// The route goes out of `device_index`, or when `None`, of the device of the
+    /// connected route reaching `gateway`.
+    pub fn add_route(
+        &self,
+        network: u32,
+        netmask: u32,
+        gateway: u32,
+        device_index: Option<u32>,
+        metric: u32,
+    ) -> Result<()> {
+        let device_index = match device_index {
+            Some(device_index) => device_index,
+            None => {
+                let routes = self
+                    .routes
+                    .read()
+                    .map_err(|_| anyhow::anyhow!("Failed to read lock"))?;
+                routes
+                    .iter()
+                    .find(|route| route.gateway == IP_ADDRESS_ANY && route.matches(gateway))
+                    .map(|route| route.device_index)
+                    .ok_or_else(|| {
+                        anyhow::anyhow!("gateway unreachable, gateway={}", Ipv4Addr::from(gateway))
+                    })?
+            }
+        };
+        self.insert_route(IPRoute {
+            network: network & netmask,
+            netmask,
+            gateway,
+            device_index,
+            metric,
+        })
+    }
+
+    /// Adds the route used when no other one matches.
+    pub fn add_default_route(
+        &self,
+        gateway: u32,
+        device_index: Option<u32>,
+        metric: u32,
+    ) -> Result<()> {
+        self.add_route(IP_ADDRESS_ANY, 0, gateway, device_index, metric)
+    }
+
+    /// Removes the routes to `network`/`netmask`, or only the one via `gateway` if given.
+    /// Returns the number of routes removed.
+    pub fn remove_route(&self, network: u32, netmask: u32, gateway: Option<u32>) -> Result<usize> {
+        let mut routes = self
+            .routes
+            .write()
+            .map_err(|_| anyhow::anyhow!("Failed to write lock"))?;
+        let before = routes.len();
+        routes.retain(|route| {
+            !(route.network == network & netmask
+                && route.netmask == netmask
+                && gateway.is_none_or(|gateway| route.gateway == gateway))
+        });
+        Ok(before - routes.len())
+    }
+
+    pub fn routes(&self) -> Result<Vec<IPRoute>> {
+        Ok(self
+            .routes
+            .read()
+            .map_err(|_| anyhow::anyhow!("Failed to read lock"))?
+            .clone())
+    }
+
+    /// Finds the route to `destination` with the longest matching prefix, the lowest
+    /// metric breaking ties.
+    pub fn lookup_route(&self, destination: u32) -> Result<Option<IPRoute>> {
+        let routes = self
+            .routes
+            .read()
+            .map_err(|_| anyhow::anyhow!("Failed to read lock"))?;
+        Ok(routes
+            .iter()
+            .filter(|route| route.matches(destination))
+            .max_by(|a, b| {
+                a.prefix_length()
+                    .cmp(&b.prefix_length())
+                    .then(b.metric.cmp(&a.metric))
+            })
+            .copied())
+    }
+
+    fn insert_route(&self, route: IPRoute) -> Result<()> {
+        let mut routes = self
+            .routes
+            .write()
+            .map_err(|_| anyhow::anyhow!("Failed to write lock"))?;
+        if routes.iter().any(|existing| {
+            existing.network == route.network
+                && existing.netmask == route.netmask
+                && existing.gateway == route.gateway
+                && existing.device_index == route.device_index
+                && existing.metric == route.metric
+        }) {
+            return Err(anyhow::anyhow!("route already exists, route={}", route));
+        }
+        info!("route added, route={}", route);
+        routes.push(route);
+        Ok(())
+    }
+
+    /// Registers the upper-layer `handler` receiving the datagrams of `protocol`
+    /// addressed to this host. Any protocol number can be registered through
+    /// `IPProtocol::from`; datagrams of unregistered protocols are answered with an
+    /// ICMP Protocol Unreachable.
+    pub fn register_protocol(
+        &self,
+        protocol: IPProtocol,
+        handler: IPProtocolHandler,
+    ) -> Result<()> {
+        let mut protocols = self
+            .protocols
+            .write()
+            .map_err(|_| anyhow::anyhow!("Failed to write lock"))?;
+        if protocols.contains_key(&u8::from(protocol)) {
+            return Err(anyhow::anyhow!(
+                "already registered, protocol={:?}",
+                protocol
+            ));
+        }
+        protocols.insert(u8::from(protocol), handler);
+        info!("registered, protocol={:?}", protocol);
+        Ok(())
+    }
+
+    pub(crate) fn input(
+        &self,
+        context: &NetDeviceContext,
+        device_index: u32,
+        data: Vec<u8>,
+    ) -> Result<()> {
+        self.statistics.received.fetch_add(1, Ordering::Relaxed);
+        let packet = match IPPacket::parse(&data) {
+            Ok(packet) => packet,
+            Err(e @ IPError::InvalidChecksum { .. }) => {
+                self.statistics
+                    .checksum_errors
+                    .fetch_add(1, Ordering::Relaxed);
+                debug!("dropped, dev={}, err={}", device_index, e);
+                return Ok(());
+            }
+            Err(e @ IPError::InvalidOption { offset, .. }) => {
+                self.statistics
+                    .header_errors
+                    .fetch_add(1, Ordering::Relaxed);
+                debug!("dropped, dev={}, err={}", device_index, e);
+                let destination = u32::from_be_bytes([data[16], data[17], data[18], data[19]]);
+                if !self.forwarding() && context.lookup_ip_interface(destination)?.is_none() {
+                    return Ok(());
+                }
+                return context
+                    .icmp_controller()
+                    .parameter_problem(context, offset as u8, &data);
+            }
+            Err(e) => {
+                self.statistics
+                    .header_errors
+                    .fetch_add(1, Ordering::Relaxed);
+                debug!("dropped, dev={}, err={}", device_index, e);
+                return Ok(());
+            }
+        };
+        let header = &packet.header;
+        debug!(
+            "input, dev={}, src={}, dst={}, protocol={:?}, len={}",
+            device_index,
+            Ipv4Addr::from(header.source_ip_address),
+            Ipv4Addr::from(header.destination_ip_address),
+            header.protocol,
+            header.total_length
+        );
+        let interfaces = context.ip_interfaces(device_index)?;
+        if interfaces.is_empty() {
+            debug!("no ip interface, dev={}", device_index);
+            return Ok(());
+        }
+        let destination = header.destination_ip_address;
+        let broadcast = destination == IP_ADDRESS_BROADCAST
+            || interfaces
+                .iter()
+                .any(|interface| interface.broadcast == destination);
+        if !broadcast && context.lookup_ip_interface(destination)?.is_none() {
+            if self.forwarding() {
+                return self.forward(context, device_index, packet, data);
+            }
+            self.statistics
+                .address_errors
+                .fetch_add(1, Ordering::Relaxed);
+            debug!(
+                "not for us, dev={}, dst={}",
+                device_index,
+                Ipv4Addr::from(header.destination_ip_address)
+            );
+            return Ok(());
+        }
+        let packet = if header.mf || header.fragment_offset != 0 {
+            match self.reassemble(packet)? {
+                Some(packet) => packet,
+                None => return Ok(()),
+            }
+        } else {
+            packet
+        };
+        let header = &packet.header;
+        let protocols = self
+            .protocols
+            .read()
+            .map_err(|_| anyhow::anyhow!("Failed to read lock"))?;
+        match protocols.get(&u8::from(header.protocol)) {
+            Some(handler) => {
+                self.statistics.delivered.fetch_add(1, Ordering::Relaxed);
+                handler(context, device_index, &packet)
+            }
+            None => {
+                self.statistics
+                    .unknown_protocols
+                    .fetch_add(1, Ordering::Relaxed);
+                debug!("unsupported protocol, protocol={:?}", header.protocol);
+                drop(protocols);
+                if broadcast {
+                    return Ok(());
+                }
+                context.icmp_controller().destination_unreachable(
+                    context,
+                    ICMP_CODE_PROTOCOL_UNREACHABLE,
+                    &packet,
+                )
+            }
+        }
+    }
+
+    /// Sends `packet`, received on `device_index` but addressed to another host, on
+    /// to its next hop, decrementing its TTL.
+    fn forward(
+        &self,
+        context: &NetDeviceContext,
+        device_index: u32,
+        mut packet: IPPacket,
+        mut datagram: Vec<u8>,
+    ) -> Result<()> {
+        let source = packet.header.source_ip_address;
+        let destination = packet.header.destination_ip_address;
+        if source == IP_ADDRESS_ANY || source == IP_ADDRESS_BROADCAST {
+            self.statistics
+                .address_errors
+                .fetch_add(1, Ordering::Relaxed);
+            debug!(
+                "not forwarded, dev={}, src={}",
+                device_index,
+                Ipv4Addr::from(source)
+            );
+            return Ok(());
+        }
+        if packet.header.ttl <= 1 {
+            self.statistics.ttl_exceeded.fetch_add(1, Ordering::Relaxed);
+            debug!(
+                "ttl exceeded, dev={}, src={}, dst={}",
+                device_index,
+                Ipv4Addr::from(source),
+                Ipv4Addr::from(destination)
+            );
+            return context.icmp_controller().time_exceeded(
+                context,
+                ICMP_CODE_TTL_EXCEEDED,
+                &packet,
+            );
+        }
+        let Some(route) = self.lookup_route(destination)? else {
+            self.statistics.no_routes.fetch_add(1, Ordering::Relaxed);
+            debug!("no route, dst={}", Ipv4Addr::from(destination));
+            return context.icmp_controller().destination_unreachable(
+                context,
+                ICMP_CODE_NET_UNREACHABLE,
+                &packet,
+            );
+        };
+        let mtu = context.mtu(route.device_index)?;
+        if packet.header.df && packet.header.total_length > mtu {
+            self.statistics
+                .fragmentation_failures
+                .fetch_add(1, Ordering::Relaxed);
+            debug!(
+                "fragmentation needed, dst={}, mtu={}, len={}",
+                Ipv4Addr::from(destination),
+                mtu,
+                packet.header.total_length
+            );
+            return context
+                .icmp_controller()
+                .fragmentation_needed(context, mtu, &packet);
+        }
+        packet.header.ttl -= 1;
+        let next_hop = route.next_hop(destination);
+        debug!(
+            "forward, dev={}->{}, src={}, dst={}, next_hop={}, ttl={}",
+            device_index,
+            route.device_index,
+            Ipv4Addr::from(source),
+            Ipv4Addr::from(destination),
+            Ipv4Addr::from(next_hop),
+            packet.header.ttl
+        );
+        // A datagram fitting the MTU goes out as received but for the TTL and the
+        // checksum, only the ones to fragment being built again.
+        let result = if packet.header.total_length <= mtu {
+            datagram.truncate(packet.header.total_length as usize);
+            decrement_ttl(&mut datagram);
+            context.transmit_ip(route.device_index, vec![datagram], next_hop)
+        } else {
+            self.transmit(context, route.device_index, next_hop, &packet)
+        };
+        if let Err(e) = result {
+            debug!(
+                "forward failed, dst={}, err={}",
+                Ipv4Addr::from(destination),
+                e
+            );
+            return Ok(());
+        }
+        self.statistics.forwarded.fetch_add(1, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Adds `fragment` to the datagram it belongs to and returns the datagram once
+    /// all of its fragments arrived.
+    ///
+    /// When the fragments held exceed `IP_REASSEMBLY_MEMORY_MAX`, the oldest
+    /// datagrams are dropped.
+    fn reassemble(&self, fragment: IPPacket) -> Result<Option<IPPacket>> {
+        self.statistics
+            .reassembly_required
+            .fetch_add(1, Ordering::Relaxed);
+        let mut reassemblies = self
+            .reassemblies
+            .lock()
+            .map_err(|_| anyhow::anyhow!("Failed to lock"))?;
+        let key = IPReassemblyKey::new(&fragment.header);
+        let reassembly = reassemblies.entry(key).or_insert_with(IPReassembly::new);
+        if let Err(e) = reassembly.insert(&fragment) {
+            reassemblies.remove(&key);
+            self.statistics
+                .reassembly_failures
+                .fetch_add(1, Ordering::Relaxed);
+            debug!(
+                "reassembly failed, src={}, id={}, err={}",
+                Ipv4Addr::from(key.source_ip_address),
+                key.identification,
+                e
+            );
+            return Ok(None);
+        }
+        if reassembly.is_complete() {
+            let packet = reassemblies
+                .remove(&key)
+                .and_then(IPReassembly::into_packet);
+            if let Some(packet) = &packet {
+                self.statistics.reassembled.fetch_add(1, Ordering::Relaxed);
+                debug!(
+                    "reassembled, src={}, id={}, len={}",
+                    Ipv4Addr::from(key.source_ip_address),
+                    key.identification,
+                    packet.header.total_length
+                );
+            }
+            return Ok(packet);
+        }
+        let mut memory: usize = reassemblies
+            .values()
+            .map(|reassembly| reassembly.data.len())
+            .sum();
+        while memory > IP_REASSEMBLY_MEMORY_MAX {
+            let Some(oldest) = reassemblies
+                .iter()
+                .min_by_key(|(_, reassembly)| reassembly.created)
+                .map(|(key, _)| *key)
+            else {
+                break;
+            };
+            if let Some(reassembly) = reassemblies.remove(&oldest) {
+                memory -= reassembly.data.len();
+            }
+            self.statistics
+                .reassembly_failures
+                .fetch_add(1, Ordering::Relaxed);
+            debug!(
+                "reassembly dropped, memory cap reached, src={}, id={}",
+                Ipv4Addr::from(oldest.source_ip_address),
+                oldest.identification
+            );
+        }
+        Ok(None)
+    }
+
+    /// Drops the datagrams whose fragments did not all arrive within
+    /// `IP_REASSEMBLY_TIMEOUT`, sending a Time Exceeded when the first fragment
+    /// was received (RFC 792).
+    pub(crate) fn reassembly_timer(&self, context: &NetDeviceContext) -> Result<()> {
+        let mut expired = Vec::new();
+        let mut reassemblies = self
+            .reassemblies
+            .lock()
+            .map_err(|_| anyhow::anyhow!("Failed to lock"))?;
+        reassemblies.retain(|key, reassembly| {
+            if reassembly.created.elapsed() < IP_REASSEMBLY_TIMEOUT {
+                return true;
+            }
+            self.statistics
+                .reassembly_failures
+                .fetch_add(1, Ordering::Relaxed);
+            debug!(
+                "reassembly timed out, src={}, id={}",
+                Ipv4Addr::from(key.source_ip_address),
+                key.identification
+            );
+            expired.extend(reassembly.first_fragment());
+            false
+        });
+        drop(reassemblies);
+        for packet in expired {
+            context.icmp_controller().time_exceeded(
+                context,
+                ICMP_CODE_REASSEMBLY_TIME_EXCEEDED,
+                &packet,
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Sends `data` to `destination` as a datagram of `protocol`, with the default
+    /// `IPOutputOptions`.
+    pub fn output(
+        &self,
+        context: &NetDeviceContext,
+        protocol: IPProtocol,
+        data: Vec<u8>,
+        source: u32,
+        destination: u32,
+    ) -> Result<()> {
+        self.output_with(
+            context,
+            protocol,
+            data,
+            source,
+            destination,
+            IPOutputOptions::default(),
+        )
+    }
+
+    /// Sends `data` to `destination` as a datagram of `protocol`.
+    ///
+    /// The outgoing device and the next hop come from the routing table, except for
+    /// the limited broadcast which goes out of the device of `source`. When `source`
+    /// is `IP_ADDRESS_ANY`, the address of an interface of the outgoing device is used.
+    /// Datagrams larger than the MTU of the device are fragmented, unless `df` is set
+    /// in `output_options`.
+    pub fn output_with(
+        &self,
+        context: &NetDeviceContext,
+        protocol: IPProtocol,
+        data: Vec<u8>,
+        source: u32,
+        destination: u32,
+        output_options: IPOutputOptions,
+    ) -> Result<()> {
+        let (device_index, next_hop, source) = self.select(context, source, destination)?;
+        let options_length = IPOption::padded_length(&output_options.options);
+        if IP_HEADER_LENGTH_MIN + options_length > IP_HEADER_LENGTH_MAX {
+            return Err(anyhow::anyhow!("too long options, len={}", options_length));
+        }
+        let total_length = IP_HEADER_LENGTH_MIN + options_length + data.len();
+        if total_length > u16::MAX as usize {
+            return Err(anyhow::anyhow!("too long, len={}", total_length));
+        }
+        let identification = self.identification.fetch_add(1, Ordering::Relaxed);
+        let mut packet = IPPacket::new(
+            protocol,
+            source,
+            destination,
+            identification,
+            output_options.ttl,
+            data,
+        );
+        packet.header.ihl = ((IP_HEADER_LENGTH_MIN + options_length) / 4) as u8;
+        packet.header.total_length = total_length as u16;
+        packet.header.df = output_options.df;
+        packet.header.options = output_options.options;
+        debug!(
+            "output, dev={}, src={}, dst={}, next_hop={}, protocol={:?}, len={}",
+            device_index,
+            Ipv4Addr::from(source),
+            Ipv4Addr::from(destination),
+            Ipv4Addr::from(next_hop),
+            protocol,
+            packet.header.total_length
+        );
+        self.transmit(context, device_index, next_hop, &packet)?;
+        self.statistics.sent.fetch_add(1, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// The source address of the datagrams sent to `destination` from
+    /// `IP_ADDRESS_ANY`.
+    pub fn source_address(&self, context: &NetDeviceContext, destination: u32) -> Result<u32> {
+        let (_, _, source) = self.select(context, IP_ADDRESS_ANY, destination)?;
+        Ok(source)
+    }
+
+    /// The outgoing device, the next hop and the source address of a datagram
+    /// from `source` to `destination`.
+    fn select(
+        &self,
+        context: &NetDeviceContext,
+        source: u32,
+        destination: u32,
+    ) -> Result<(u32, u32, u32)> {
+        let (device_index, next_hop) = if destination == IP_ADDRESS_BROADCAST {
+            let interfaces = context.all_ip_interfaces()?;
+            let (device_index, _) = interfaces
+                .iter()
+                .find(|(_, interface)| source == IP_ADDRESS_ANY || interface.unicast == source)
+                .ok_or_else(|| anyhow::anyhow!("no interface, src={}", Ipv4Addr::from(source)))?;
+            (*device_index, destination)
+        } else {
+            let route = self
+                .lookup_route(destination)?
+                .ok_or(IPError::NoRoute(destination))?;
+            (route.device_index, route.next_hop(destination))
+        };
+        let source = if source == IP_ADDRESS_ANY {
+            let interfaces = context.ip_interfaces(device_index)?;
+            interfaces
+                .iter()
+                .find(|interface| interface.contains(next_hop))
+                .or_else(|| interfaces.first())
+                .ok_or_else(|| anyhow::anyhow!("no interface, dev={}", device_index))?
+                .unicast
+        } else {
+            if context.lookup_ip_interface(source)?.is_none() {
+                return Err(anyhow::anyhow!(
+                    "not a local address, src={}",
+                    Ipv4Addr::from(source)
+                ));
+            }
+            source
+        };
+        Ok((device_index, next_hop, source))
+    }
+
+    /// Hands `packet` to the device, fragmenting it to the MTU of the device.
+    fn transmit(
+        &self,
+        context: &NetDeviceContext,
+        device_index: u32,
+        next_hop: u32,
+        packet: &IPPacket,
+    ) -> Result<()> {
+        let mtu = context.mtu(device_index)?;
+        let fragments = match packet.fragment(mtu) {
+            Ok(fragments) => fragments,
+            Err(e) => {
+                self.statistics
+                    .fragmentation_failures
+                    .fetch_add(1, Ordering::Relaxed);
+                return Err(e.into());
+            }
+        };
+        if fragments.len() > 1 {
+            debug!(
+                "fragmented, dev={}, mtu={}, id={}, fragments={}",
+                device_index,
+                mtu,
+                packet.header.identification,
+                fragments.len()
+            );
+            self.statistics.fragmented.fetch_add(1, Ordering::Relaxed);
+            self.statistics
+                .fragments_created
+                .fetch_add(fragments.len() as u64, Ordering::Relaxed);
+        }
+        // Handed over together, so that ARP queues all of them or none.
+        let fragments = fragments.iter().map(IPPacket::serialize).collect();
+        context.transmit_ip(device_index, fragments, next_hop)
+    }
 }
 
-// pub struct IPController {}
-//
-// impl IPController {
-//     pub fn new() -> Self {
-//         IPController {}
-//     }
-//
-//     pub fn input(&self, data: Vec<u8>) {}
-// }
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A datagram from 192.0.2.1 to `destination` carrying `data`, with
+    /// `options` as the raw options area.
+    fn datagram(destination: [u8; 4], ttl: u8, options: &[u8], data: &[u8]) -> Vec<u8> {
+        let header_length = IP_HEADER_LENGTH_MIN + options.len();
+        let total_length = (header_length + data.len()) as u16;
+        let mut datagram = vec![0x40 | (header_length / 4) as u8, 0];
+        datagram.extend_from_slice(&total_length.to_be_bytes());
+        datagram.extend_from_slice(&[0x12, 0x34, 0x00, 0x00, ttl, 17, 0, 0]);
+        datagram.extend_from_slice(&[192, 0, 2, 1]);
+        datagram.extend_from_slice(&destination);
+        datagram.extend_from_slice(options);
+        let sum = checksum(&datagram, 0);
+        datagram[10..12].copy_from_slice(&sum.to_be_bytes());
+        datagram.extend_from_slice(data);
+        datagram
+    }
+
+    #[test]
+    fn checksum_matches_rfc_1071() {
+        // Worked example of RFC 1071 section 3, whose sum is 0xddf2.
+        let data = [0x00, 0x01, 0xf2, 0x03, 0xf4, 0xf5, 0xf6, 0xf7];
+        assert_eq!(checksum(&data, 0), !0xddf2);
+        let mut verified = data.to_vec();
+        verified.extend_from_slice(&checksum(&data, 0).to_be_bytes());
+        assert_eq!(checksum(&verified, 0), 0);
+        // An odd byte is padded with a zero.
+        assert_eq!(checksum(&data[..3], 0), !0xf201);
+        assert_eq!(
+            checksum(&data[..3], 0),
+            checksum(&[0x00, 0x01, 0xf2, 0x00], 0)
+        );
+        // Chaining the sum of a first part.
+        assert_eq!(
+            checksum(&data[4..], !checksum(&data[..4], 0) as u32),
+            checksum(&data, 0)
+        );
+    }
+
+    #[test]
+    fn decrement_ttl_matches_recomputed_checksum() {
+        for identification in (0..=u16::MAX).step_by(251) {
+            for ttl in 1..=u8::MAX {
+                let mut data = datagram([192, 0, 2, 2], ttl, &[], &[]);
+                data[4..6].copy_from_slice(&identification.to_be_bytes());
+                data[10..12].copy_from_slice(&[0, 0]);
+                let sum = checksum(&data, 0);
+                data[10..12].copy_from_slice(&sum.to_be_bytes());
+                decrement_ttl(&mut data);
+                let mut expected = data.clone();
+                expected[10..12].copy_from_slice(&[0, 0]);
+                let sum = checksum(&expected, 0);
+                expected[10..12].copy_from_slice(&sum.to_be_bytes());
+                assert_eq!(data, expected, "id={}, ttl={}", identification, ttl);
+            }
+        }
+    }
+
+    #[test]
+    fn parse_rejects_malformed_headers() {
+        let valid = datagram([192, 0, 2, 2], 64, &[], &[1, 2, 3, 4]);
+        // `valid` with the byte at `offset` set to `value`, the checksum updated.
+        let with = |offset: usize, value: u8| {
+            let mut data = valid.clone();
+            data[offset] = value;
+            data[10..12].copy_from_slice(&[0, 0]);
+            let sum = checksum(&data[..IP_HEADER_LENGTH_MIN], 0);
+            data[10..12].copy_from_slice(&sum.to_be_bytes());
+            data
+        };
+        let cases = [
+            (Vec::new(), IPError::TooShort { len: 0 }),
+            (
+                valid[..IP_HEADER_LENGTH_MIN - 1].to_vec(),
+                IPError::TooShort { len: 19 },
+            ),
+            (with(0, 0x65), IPError::InvalidVersion(6)),
+            (with(0, 0x05), IPError::InvalidVersion(0)),
+            (with(0, 0x40), IPError::InvalidHeaderLength(0)),
+            (with(0, 0x44), IPError::InvalidHeaderLength(4)),
+            (with(0, 0x47), IPError::InvalidHeaderLength(7)),
+            (with(0, 0x4f), IPError::InvalidHeaderLength(15)),
+            (
+                with(3, 19),
+                IPError::InvalidTotalLength {
+                    total_length: 19,
+                    len: 24,
+                },
+            ),
+            (
+                with(3, 25),
+                IPError::InvalidTotalLength {
+                    total_length: 25,
+                    len: 24,
+                },
+            ),
+            (
+                with(2, 0xff),
+                IPError::InvalidTotalLength {
+                    total_length: 0xff18,
+                    len: 24,
+                },
+            ),
+        ];
+        for (data, error) in cases {
+            assert_eq!(
+                IPPacket::parse(&data).unwrap_err(),
+                error,
+                "data={:02x?}",
+                data
+            );
+        }
+    }
+
+    #[test]
+    fn lookup_route_prefers_longest_prefix() {
+        let controller = IPController::new();
+        controller
+            .add_default_route(0xc0000201, Some(0), 0)
+            .unwrap();
+        controller
+            .add_route(0xc6330000, 0xffff0000, 0xc0000202, Some(1), 0)
+            .unwrap();
+        controller
+            .add_route(0xc6336400, 0xffffff00, 0xc0000203, Some(2), 10)
+            .unwrap();
+        let device = |destination| {
+            controller
+                .lookup_route(destination)
+                .unwrap()
+                .unwrap()
+                .device_index
+        };
+        assert_eq!(device(0xc6336401), 2);
+        assert_eq!(device(0xc6336501), 1);
+        assert_eq!(device(0xcb007101), 0);
+        assert_eq!(
+            controller
+                .lookup_route(0xc6336401)
+                .unwrap()
+                .unwrap()
+                .next_hop(0xc6336401),
+            0xc0000203
+        );
+    }
+
+    #[test]
+    fn lookup_route_prefers_lowest_metric() {
+        let controller = IPController::new();
+        controller
+            .add_route(0xc6336400, 0xffffff00, 0xc0000202, Some(0), 20)
+            .unwrap();
+        controller
+            .add_route(0xc6336400, 0xffffff00, 0xc0000203, Some(1), 10)
+            .unwrap();
+        controller
+            .add_route(0xc6336400, 0xffffff00, 0xc0000204, Some(2), 30)
+            .unwrap();
+        let route = controller.lookup_route(0xc6336401).unwrap().unwrap();
+        assert_eq!((route.device_index, route.metric), (1, 10));
+    }
+
+    #[test]
+    fn lookup_route_fails_without_match() {
+        let (context, _) = crate::net::tests::context(&["192.0.2.1/24"]);
+        let controller = context.ip_controller();
+        assert!(controller.lookup_route(0xc6336401).unwrap().is_none());
+        let e = controller
+            .output(
+                &context,
+                IPProtocol::UDP,
+                vec![0; 8],
+                IP_ADDRESS_ANY,
+                0xc6336401,
+            )
+            .unwrap_err();
+        assert_eq!(
+            e.downcast_ref::<IPError>(),
+            Some(&IPError::NoRoute(0xc6336401))
+        );
+        assert!(controller.lookup_route(0xc0000202).unwrap().is_some());
+    }
+
+    /// A datagram from 192.0.2.1 to 192.0.2.2 with `length` bytes of data.
+    fn packet(length: usize) -> IPPacket {
+        let data = (0..length).map(|i| i as u8).collect();
+        IPPacket::new(IPProtocol::UDP, 0xc0000201, 0xc0000202, 1, 64, data)
+    }
+
+    #[test]
+    fn fragment_cuts_data_at_multiples_of_8() {
+        let packet = packet(100);
+        // Room for 44 bytes of data, cut down to 40.
+        let fragments = packet.fragment((IP_HEADER_LENGTH_MIN + 44) as u16).unwrap();
+        let layout: Vec<(usize, u16, bool)> = fragments
+            .iter()
+            .map(|fragment| {
+                (
+                    fragment.data().len(),
+                    fragment.header().fragment_offset(),
+                    fragment.header().mf(),
+                )
+            })
+            .collect();
+        assert_eq!(layout, [(40, 0, true), (40, 5, true), (20, 10, false)]);
+        for fragment in &fragments {
+            assert_eq!(
+                fragment.header().total_length() as usize,
+                IP_HEADER_LENGTH_MIN + fragment.data().len()
+            );
+        }
+        let data: Vec<u8> = fragments
+            .iter()
+            .flat_map(|fragment| fragment.data().to_vec())
+            .collect();
+        assert_eq!(data, packet.data());
+    }
+
+    #[test]
+    fn fragment_copies_only_copied_options() {
+        let mut packet = packet(64);
+        let source_route = IPOption::LooseSourceRoute {
+            pointer: 4,
+            route: vec![0xc6336401],
+        };
+        packet.header.options = vec![source_route.clone(), IPOption::record_route(1)];
+        let header_length = IP_HEADER_LENGTH_MIN + IPOption::padded_length(&packet.header.options);
+        packet.header.ihl = (header_length / 4) as u8;
+        packet.header.total_length = (header_length + 64) as u16;
+        let fragments = packet.fragment((header_length + 32) as u16).unwrap();
+        assert!(fragments.len() > 1);
+        assert_eq!(fragments[0].header().options(), packet.header.options);
+        for fragment in &fragments[1..] {
+            assert_eq!(
+                fragment.header().options(),
+                std::slice::from_ref(&source_route)
+            );
+            let data = fragment.serialize();
+            assert_eq!(IPPacket::parse(&data).unwrap().data(), fragment.data());
+        }
+    }
+
+    #[test]
+    fn fragment_of_fragment_keeps_mf() {
+        let mut packet = packet(64);
+        packet.header.mf = true;
+        packet.header.fragment_offset = 10;
+        let fragments = packet.fragment((IP_HEADER_LENGTH_MIN + 32) as u16).unwrap();
+        let layout: Vec<(u16, bool)> = fragments
+            .iter()
+            .map(|fragment| (fragment.header().fragment_offset(), fragment.header().mf()))
+            .collect();
+        assert_eq!(layout, [(10, true), (14, true)]);
+        packet.header.mf = false;
+        let fragments = packet.fragment((IP_HEADER_LENGTH_MIN + 32) as u16).unwrap();
+        assert!(!fragments.last().unwrap().header().mf());
+    }
+
+    #[test]
+    fn fragment_fails_with_df() {
+        let mut packet = packet(100);
+        packet.header.df = true;
+        assert_eq!(
+            packet.fragment(100).unwrap_err(),
+            IPError::FragmentationNeeded { mtu: 100 }
+        );
+        assert_eq!(packet.fragment(120).unwrap().len(), 1);
+    }
+
+    /// A fragment of datagram `identification` from 192.0.2.1, starting at
+    /// `offset` bytes into the data.
+    fn fragment(identification: u16, offset: usize, mf: bool, data: &[u8]) -> IPPacket {
+        let mut packet = IPPacket::new(
+            IPProtocol::UDP,
+            0xc0000201,
+            0xc0000202,
+            identification,
+            64,
+            data.to_vec(),
+        );
+        packet.header.mf = mf;
+        packet.header.fragment_offset = (offset / 8) as u16;
+        packet
+    }
+
+    #[test]
+    fn reassemble_out_of_order() {
+        let controller = IPController::new();
+        assert!(controller
+            .reassemble(fragment(1, 16, false, &[3; 4]))
+            .unwrap()
+            .is_none());
+        assert!(controller
+            .reassemble(fragment(1, 0, true, &[1; 8]))
+            .unwrap()
+            .is_none());
+        let packet = controller
+            .reassemble(fragment(1, 8, true, &[2; 8]))
+            .unwrap()
+            .unwrap();
+        assert_eq!(packet.data(), [&[1; 8][..], &[2; 8], &[3; 4]].concat());
+        assert_eq!(
+            packet.header().total_length() as usize,
+            IP_HEADER_LENGTH_MIN + 20
+        );
+        assert!(!packet.header().mf());
+        assert_eq!(packet.header().fragment_offset(), 0);
+        assert!(controller.reassemblies.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn reassemble_keeps_bytes_received_first() {
+        let controller = IPController::new();
+        controller
+            .reassemble(fragment(1, 8, true, &[2; 16]))
+            .unwrap();
+        controller
+            .reassemble(fragment(1, 0, true, &[1; 16]))
+            .unwrap();
+        let packet = controller
+            .reassemble(fragment(1, 16, false, &[3; 16]))
+            .unwrap()
+            .unwrap();
+        let expected = [&[1; 8][..], &[2; 16], &[3; 8]].concat();
+        assert_eq!(packet.data(), expected);
+    }
+
+    #[test]
+    fn reassemble_drops_contradicting_last_fragment() {
+        let controller = IPController::new();
+        controller
+            .reassemble(fragment(1, 0, true, &[1; 8]))
+            .unwrap();
+        controller
+            .reassemble(fragment(1, 16, false, &[3; 8]))
+            .unwrap();
+        // Another last fragment ending elsewhere.
+        assert!(controller
+            .reassemble(fragment(1, 24, false, &[4; 8]))
+            .unwrap()
+            .is_none());
+        assert!(controller.reassemblies.lock().unwrap().is_empty());
+        assert_eq!(
+            controller
+                .statistics()
+                .reassembly_failures
+                .load(Ordering::Relaxed),
+            1
+        );
+        // The missing fragment now starts a datagram of its own.
+        assert!(controller
+            .reassemble(fragment(1, 8, true, &[2; 8]))
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn reassemble_drops_oldest_past_memory_cap() {
+        let controller = IPController::new();
+        // Fragments far into their datagram, each holding about a fifth of the cap.
+        let offset = IP_REASSEMBLY_MEMORY_MAX / 5 / 8 * 8;
+        let count = 5;
+        for identification in 0..count {
+            controller
+                .reassemble(fragment(identification, offset, true, &[0; 8]))
+                .unwrap();
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        let reassemblies = controller.reassemblies.lock().unwrap();
+        let held: usize = reassemblies
+            .values()
+            .map(|reassembly| reassembly.data.len())
+            .sum();
+        assert!(held <= IP_REASSEMBLY_MEMORY_MAX);
+        assert_eq!(reassemblies.len(), count as usize - 1);
+        assert!(!reassemblies.contains_key(&IPReassemblyKey::new(
+            &fragment(0, offset, true, &[0; 8]).header
+        )));
+        assert_eq!(
+            controller
+                .statistics()
+                .reassembly_failures
+                .load(Ordering::Relaxed),
+            1
+        );
+    }
+
+    #[test]
+    fn serialize_keeps_bytes_after_end() {
+        let options = [IP_OPTION_TYPE_NOP, IP_OPTION_TYPE_END, 0, 0, 0x5a, 0, 0, 0];
+        let data = datagram([192, 0, 2, 2], 64, &options, &[1, 2, 3, 4]);
+        let packet = IPPacket::parse(&data).unwrap();
+        assert_eq!(
+            packet.header().options(),
+            &[IPOption::NoOperation, IPOption::End]
+        );
+        assert_eq!(packet.serialize(), data);
+    }
+
+    #[test]
+    fn serialize_computes_total_length() {
+        let options = [IP_OPTION_TYPE_NOP, IP_OPTION_TYPE_END, 0, 0];
+        let mut packet =
+            IPPacket::parse(&datagram([192, 0, 2, 2], 64, &options, &[1, 2, 3, 4])).unwrap();
+        packet.header.options = Vec::new();
+        packet.header.padding = Vec::new();
+        let data = packet.serialize();
+        assert_eq!(data.len(), IP_HEADER_LENGTH_MIN + 4);
+        let packet = IPPacket::parse(&data).unwrap();
+        assert_eq!(packet.header().total_length() as usize, data.len());
+        assert_eq!(packet.data(), &[1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn forward_only_changes_ttl_and_checksum() {
+        let (context, sent) = crate::net::tests::context(&["192.0.2.254/24", "198.51.100.254/24"]);
+        context.ip_controller().set_forwarding(true);
+        let options = [IP_OPTION_TYPE_NOP, IP_OPTION_TYPE_END, 0x5a, 0];
+        let data = datagram([198, 51, 100, 1], 64, &options, &[1, 2, 3, 4]);
+        context
+            .ip_controller()
+            .input(&context, 0, data.clone())
+            .unwrap();
+        let forwarded = sent[1].lock().unwrap().pop().unwrap();
+        assert_eq!(forwarded.len(), data.len());
+        for (offset, (byte, original)) in forwarded.iter().zip(&data).enumerate() {
+            if ![8, 10, 11].contains(&offset) {
+                assert_eq!(byte, original, "offset={}", offset);
+            }
+        }
+        assert_eq!(forwarded[8], 63);
+        assert_eq!(
+            checksum(&forwarded[..IP_HEADER_LENGTH_MIN + options.len()], 0),
+            0
+        );
+    }
+}
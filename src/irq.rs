@@ -1,13 +1,17 @@
 use anyhow::Result;
-use log::{debug, info};
+use log::{debug, error, info};
 use signal_hook::{
-    consts::{SIGHUP, SIGUSR1},
+    consts::{SIGALRM, SIGHUP, SIGUSR1},
     iterator::Signals,
     low_level,
 };
 use std::{
-    sync::{Arc, RwLock, Weak},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, RwLock, Weak,
+    },
     thread,
+    time::Duration,
 };
 
 use crate::net::NetDeviceContext;
@@ -19,19 +23,24 @@ pub struct IRQEntry {
 pub struct IRQContext {
     net_device_context: Weak<NetDeviceContext>,
     irq_entries: Arc<RwLock<Vec<RwLock<IRQEntry>>>>,
+    timer_running: Arc<AtomicBool>,
 }
 impl Default for IRQContext {
     fn default() -> Self {
         IRQContext {
             net_device_context: Weak::new(),
             irq_entries: Arc::new(RwLock::new(Vec::new())),
+            timer_running: Arc::new(AtomicBool::new(false)),
         }
     }
 }
 
 impl IRQContext {
-    const AVAILABLE_IRQ_MIN: i32 = 35;
-    const AVAILABLE_IRQ_MAX: i32 = 64;
+    pub(crate) const AVAILABLE_IRQ_MIN: i32 = 35;
+    /// End of the IRQ range, excluded.
+    pub(crate) const AVAILABLE_IRQ_MAX: i32 = 64;
+    /// Resolution of the timers registered on the `NetDeviceContext`.
+    const TIMER_INTERVAL: Duration = Duration::from_millis(100);
     pub fn new() -> IRQContext {
         Self::default()
     }
@@ -43,6 +52,9 @@ impl IRQContext {
         Ok(())
     }
     pub fn register(&self, irq: i32) -> Result<()> {
+        if !(Self::AVAILABLE_IRQ_MIN..Self::AVAILABLE_IRQ_MAX).contains(&irq) {
+            return Err(anyhow::anyhow!("invalid irq, irq={}", irq));
+        }
         let irq_entry = IRQEntry { irq };
         self.irq_entries
             .write()
@@ -53,7 +65,7 @@ impl IRQContext {
     pub fn run(&self) -> Result<()> {
         let available_irqs =
             (Self::AVAILABLE_IRQ_MIN..Self::AVAILABLE_IRQ_MAX).collect::<Vec<i32>>();
-        let mut signal_list = vec![SIGHUP, SIGUSR1];
+        let mut signal_list = vec![SIGHUP, SIGUSR1, SIGALRM];
         signal_list.extend(&available_irqs);
         let mut signals = Signals::new(&signal_list)?;
         let net_device_context_clone = self.net_device_context.upgrade();
@@ -62,9 +74,17 @@ impl IRQContext {
             for signal in signals.forever() {
                 if signal == SIGHUP {
                     break;
+                } else if signal == SIGALRM {
+                    if let Some(net_device_context) = &net_device_context_clone {
+                        if let Err(e) = net_device_context.timer_isr() {
+                            error!("timer isr failed, err={}", e);
+                        }
+                    }
                 } else if signal == SIGUSR1 {
                     if let Some(net_device_context) = &net_device_context_clone {
-                        net_device_context.software_isr();
+                        if let Err(e) = net_device_context.software_isr() {
+                            error!("software isr failed, err={}", e);
+                        }
                     }
                 } else {
                     for irq_entry in irq_entries_clone.read().unwrap().iter() {
@@ -77,10 +97,21 @@ impl IRQContext {
                 }
             }
         });
+        self.timer_running.store(true, Ordering::SeqCst);
+        let timer_running_clone = self.timer_running.clone();
+        thread::spawn(move || {
+            while timer_running_clone.load(Ordering::SeqCst) {
+                thread::sleep(Self::TIMER_INTERVAL);
+                if let Err(e) = raise_irq(SIGALRM) {
+                    error!("raise timer irq failed, err={}", e);
+                }
+            }
+        });
         debug!("terminated");
         Ok(())
     }
     pub fn shutdown(&self) -> Result<()> {
+        self.timer_running.store(false, Ordering::SeqCst);
         raise_irq(SIGHUP)?;
         // TODO: wait for the thread to finish
         Ok(())
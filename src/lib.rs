@@ -1,5 +1,8 @@
 pub mod arp;
 pub mod ethernet;
+pub mod icmp;
 pub mod ip;
 pub mod irq;
 pub mod net;
+pub mod ping;
+pub mod traceroute;
@@ -1,18 +1,47 @@
 use std::{
     collections::HashMap,
-    sync::{atomic::AtomicU32, Arc, Mutex, RwLock},
+    fmt::Debug,
+    fs::{File, OpenOptions},
+    io::{Read, Write},
+    net::Ipv4Addr,
+    os::fd::AsRawFd,
+    sync::{
+        atomic::{AtomicBool, AtomicU32},
+        Arc, Mutex, RwLock,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
 };
 
 use anyhow::Result;
 use log::{debug, error, info};
 use signal_hook::consts::SIGUSR1;
 
-use crate::irq::{raise_irq, IRQContext};
+use crate::{
+    arp::{ARPEthernetIPContext, ARP_SWEEP_INTERVAL, ARP_TIMER_INTERVAL},
+    ethernet::{
+        self, ETHERNET_ADDRESS_BROADCAST, ETHERNET_FRAME_SIZE_MAX, ETHERNET_PAYLOAD_SIZE_MAX,
+        ETHERNET_TYPE_ARP, ETHERNET_TYPE_IP,
+    },
+    icmp::ICMPController,
+    ip::{
+        IPController, IPInterface, IPProtocol, IP_ADDRESS_BROADCAST, IP_REASSEMBLY_TIMER_INTERVAL,
+    },
+    irq::{raise_irq, IRQContext},
+};
 
 const DUMMY_IRQ: i32 = 35;
 const LOOPBACK_IRQ: i32 = 36;
+/// First IRQ given to the drivers without a fixed one, e.g. TAP devices.
+const DYNAMIC_IRQ_MIN: i32 = 37;
+
+pub const NET_PROTOCOL_IP: u16 = ETHERNET_TYPE_IP;
+pub const NET_PROTOCOL_ARP: u16 = ETHERNET_TYPE_ARP;
 
-pub const NET_PROTOCOL_IP: u16 = 0x0800;
+pub const NET_DEVICE_FLAG_UP: u16 = 0x0001;
+pub const NET_DEVICE_FLAG_LOOPBACK: u16 = 0x0010;
+pub const NET_DEVICE_FLAG_BROADCAST: u16 = 0x0020;
+pub const NET_DEVICE_FLAG_NEED_ARP: u16 = 0x0100;
 
 pub struct NetDeviceContext {
     current_index: AtomicU32,
@@ -20,6 +49,10 @@ pub struct NetDeviceContext {
     irq_device_map: RwLock<HashMap<i32, u32>>,
     irq_context: RwLock<IRQContext>,
     protocols: RwLock<Vec<NetProtocol>>,
+    arp_context: ARPEthernetIPContext,
+    timers: RwLock<Vec<NetTimer>>,
+    ip_controller: IPController,
+    icmp_controller: ICMPController,
 }
 
 impl NetDeviceContext {
@@ -30,12 +63,34 @@ impl NetDeviceContext {
             irq_device_map: RwLock::new(HashMap::new()),
             irq_context: RwLock::new(IRQContext::new()),
             protocols: RwLock::new(Vec::new()),
+            arp_context: ARPEthernetIPContext::new(),
+            timers: RwLock::new(Vec::new()),
+            ip_controller: IPController::new(),
+            icmp_controller: ICMPController::new(),
         });
         context
             .irq_context
             .write()
             .map_err(|_| anyhow::anyhow!("Failed to write lock"))?
             .set_net_device_context(context.clone());
+        context.register_timer(
+            ARP_TIMER_INTERVAL,
+            Box::new(|context| context.arp_context.timer(context)),
+        )?;
+        context.register_timer(
+            ARP_SWEEP_INTERVAL,
+            Box::new(|context| context.arp_context.sweep()),
+        )?;
+        context.register_timer(
+            IP_REASSEMBLY_TIMER_INTERVAL,
+            Box::new(|context| context.ip_controller.reassembly_timer(context)),
+        )?;
+        context.ip_controller.register_protocol(
+            IPProtocol::ICMP,
+            Box::new(|context, device_index, packet| {
+                context.icmp_controller.input(context, device_index, packet)
+            }),
+        )?;
         Ok(context)
     }
     pub fn init(&self) -> Result<()> {
@@ -46,36 +101,35 @@ impl NetDeviceContext {
         info!("initialized");
         Ok(())
     }
+    /// Registers the device of `net_driver`, giving it the first free IRQ from
+    /// `DYNAMIC_IRQ_MIN` if it has no fixed one.
     pub fn register(
         &self,
-        net_device_type: NetDeviceType,
+        mut net_driver: Box<dyn NetDriver>,
         context: Arc<NetDeviceContext>,
     ) -> Result<()> {
         let index = self.current_index.load(std::sync::atomic::Ordering::SeqCst);
         let name = format!("net{}", index);
-        match net_device_type {
-            NetDeviceType::Dummy => {
-                self.irq_context
-                    .read()
-                    .map_err(|_| anyhow::anyhow!("Failed to read lock"))?
-                    .register(DUMMY_IRQ)?;
-                self.irq_device_map
-                    .write()
-                    .map_err(|_| anyhow::anyhow!("Failed to write lock"))?
-                    .insert(DUMMY_IRQ, index);
-            }
-            NetDeviceType::Loopback(_) => {
-                self.irq_context
-                    .read()
-                    .map_err(|_| anyhow::anyhow!("Failed to read lock"))?
-                    .register(LOOPBACK_IRQ)?;
-                self.irq_device_map
-                    .write()
-                    .map_err(|_| anyhow::anyhow!("Failed to write lock"))?
-                    .insert(LOOPBACK_IRQ, index);
+        let mut irq_device_map = self
+            .irq_device_map
+            .write()
+            .map_err(|_| anyhow::anyhow!("Failed to write lock"))?;
+        let irq = match net_driver.irq() {
+            Some(irq) if irq_device_map.contains_key(&irq) => {
+                return Err(anyhow::anyhow!("irq already in use, irq={}", irq));
             }
-        }
-        let net_device = NetDevice::new(name, net_device_type, context);
+            Some(irq) => irq,
+            None => (DYNAMIC_IRQ_MIN..IRQContext::AVAILABLE_IRQ_MAX)
+                .find(|irq| !irq_device_map.contains_key(irq))
+                .ok_or_else(|| anyhow::anyhow!("no irq available, dev={}", name))?,
+        };
+        self.irq_context
+            .read()
+            .map_err(|_| anyhow::anyhow!("Failed to read lock"))?
+            .register(irq)?;
+        net_driver.set_irq(irq);
+        irq_device_map.insert(irq, index);
+        let net_device = NetDevice::new(index, name, net_driver, context);
         self.net_devices
             .write()
             .map_err(|_| anyhow::anyhow!("Failed to write lock"))?
@@ -133,7 +187,13 @@ impl NetDeviceContext {
             .shutdown()?;
         Ok(())
     }
-    pub fn transmit(&self, index: u32, net_protocol_type: u16, data: String) -> Result<()> {
+    pub fn transmit(
+        &self,
+        index: u32,
+        net_protocol_type: u16,
+        data: Vec<u8>,
+        destination: &[u8],
+    ) -> Result<()> {
         if let Some(net_device) = self
             .net_devices
             .read()
@@ -143,7 +203,43 @@ impl NetDeviceContext {
             net_device
                 .write()
                 .map_err(|_| anyhow::anyhow!("Failed to write lock"))?
-                .transmit(net_protocol_type, data)?;
+                .transmit(net_protocol_type, data, destination)?;
+        }
+        Ok(())
+    }
+    /// Registers `handler` to be run every `interval` from the IRQ thread.
+    pub fn register_timer(&self, interval: Duration, handler: NetTimerHandler) -> Result<()> {
+        self.timers
+            .write()
+            .map_err(|_| anyhow::anyhow!("Failed to write lock"))?
+            .push(NetTimer {
+                interval,
+                last: Mutex::new(Instant::now()),
+                handler,
+            });
+        debug!("timer registered, interval={:?}", interval);
+        Ok(())
+    }
+    pub fn timer_isr(&self) -> Result<()> {
+        let timers = self
+            .timers
+            .read()
+            .map_err(|_| anyhow::anyhow!("Failed to read lock"))?;
+        let now = Instant::now();
+        for timer in &*timers {
+            {
+                let mut last = timer
+                    .last
+                    .lock()
+                    .map_err(|_| anyhow::anyhow!("Failed to lock"))?;
+                if now.duration_since(*last) < timer.interval {
+                    continue;
+                }
+                *last = now;
+            }
+            if let Err(e) = (timer.handler)(self) {
+                error!("timer handler failed, err={}", e);
+            }
         }
         Ok(())
     }
@@ -174,15 +270,31 @@ impl NetDeviceContext {
             .read()
             .map_err(|_| anyhow::anyhow!("Failed to read lock"))?;
         for protocol in &*protocols {
-            while let Some(data) = protocol
-                .queue
-                .lock()
-                .map_err(|_| anyhow::anyhow!("Failed to lock"))?
-                .pop()
-            {
+            loop {
+                let entry = protocol
+                    .queue
+                    .lock()
+                    .map_err(|_| anyhow::anyhow!("Failed to lock"))?
+                    .pop();
+                let Some(entry) = entry else {
+                    break;
+                };
                 match protocol.protocol_type {
                     NET_PROTOCOL_IP => {
-                        debug!("software isr, protocol=IP, data={}", data);
+                        if let Err(e) =
+                            self.ip_controller
+                                .input(self, entry.device_index, entry.data)
+                        {
+                            error!("software isr, protocol=IP, err={}", e);
+                        }
+                    }
+                    NET_PROTOCOL_ARP => {
+                        if let Err(e) =
+                            self.arp_context
+                                .input(self, entry.device_index, &entry.data)
+                        {
+                            error!("software isr, protocol=ARP, err={}", e);
+                        }
                     }
                     _ => {
                         error!(
@@ -195,7 +307,7 @@ impl NetDeviceContext {
         }
         Ok(())
     }
-    pub fn input(&self, protocol_type: u16, data: String) -> Result<()> {
+    pub fn input(&self, device_index: u32, protocol_type: u16, data: Vec<u8>) -> Result<()> {
         let protocols = self
             .protocols
             .read()
@@ -206,34 +318,232 @@ impl NetDeviceContext {
                     .queue
                     .lock()
                     .map_err(|_| anyhow::anyhow!("Failed to lock"))?
-                    .push(data);
+                    .push(NetProtocolQueueEntry { device_index, data });
                 raise_irq(SIGUSR1)?;
-                break;
+                return Ok(());
+            }
+        }
+        debug!("unsupported protocol, type=0x{:04x}", protocol_type);
+        Ok(())
+    }
+    /// Assigns `interface` to the device, like `ip addr add`. An address can only be
+    /// assigned once across all the devices.
+    ///
+    /// A second address on a network the device already has is a secondary
+    /// address, sharing the connected route of the first one.
+    pub fn add_ip_interface(&self, index: u32, interface: IPInterface) -> Result<()> {
+        let net_devices = self
+            .net_devices
+            .read()
+            .map_err(|_| anyhow::anyhow!("Failed to read lock"))?;
+        for net_device in &*net_devices {
+            let net_device = net_device
+                .read()
+                .map_err(|_| anyhow::anyhow!("Failed to read lock"))?;
+            if net_device
+                .interfaces
+                .iter()
+                .any(|assigned| assigned.unicast == interface.unicast)
+            {
+                return Err(anyhow::anyhow!(
+                    "address already assigned, dev={}, address={}",
+                    net_device.name,
+                    Ipv4Addr::from(interface.unicast)
+                ));
+            }
+        }
+        let mut net_device = net_devices
+            .get(index as usize)
+            .ok_or_else(|| anyhow::anyhow!("no such device, index={}", index))?
+            .write()
+            .map_err(|_| anyhow::anyhow!("Failed to write lock"))?;
+        if !net_device
+            .interfaces
+            .iter()
+            .any(|assigned| assigned.same_network(&interface))
+        {
+            self.ip_controller.add_connected_route(index, &interface)?;
+        }
+        info!("dev={}, interface={}", net_device.name, interface);
+        net_device.interfaces.push(interface);
+        Ok(())
+    }
+    /// Removes the interface holding `unicast` from the device, like `ip addr del`.
+    pub fn remove_ip_interface(&self, index: u32, unicast: u32) -> Result<IPInterface> {
+        let net_devices = self
+            .net_devices
+            .read()
+            .map_err(|_| anyhow::anyhow!("Failed to read lock"))?;
+        let mut net_device = net_devices
+            .get(index as usize)
+            .ok_or_else(|| anyhow::anyhow!("no such device, index={}", index))?
+            .write()
+            .map_err(|_| anyhow::anyhow!("Failed to write lock"))?;
+        let position = net_device
+            .interfaces
+            .iter()
+            .position(|interface| interface.unicast == unicast)
+            .ok_or_else(|| {
+                anyhow::anyhow!(
+                    "address not assigned, dev={}, address={}",
+                    net_device.name,
+                    Ipv4Addr::from(unicast)
+                )
+            })?;
+        let interface = net_device.interfaces.remove(position);
+        info!("dev={}, removed interface={}", net_device.name, interface);
+        // The route stays while a secondary address on the device still needs it.
+        if !net_device
+            .interfaces
+            .iter()
+            .any(|remaining| remaining.same_network(&interface))
+        {
+            self.ip_controller
+                .remove_connected_route(index, &interface)?;
+        }
+        Ok(interface)
+    }
+    pub fn ip_interfaces(&self, index: u32) -> Result<Vec<IPInterface>> {
+        self.with_net_device(index, |net_device| net_device.interfaces.clone())
+    }
+    /// Lists the interfaces of all the devices along with the index of their device.
+    pub fn all_ip_interfaces(&self) -> Result<Vec<(u32, IPInterface)>> {
+        let net_devices = self
+            .net_devices
+            .read()
+            .map_err(|_| anyhow::anyhow!("Failed to read lock"))?;
+        let mut interfaces = Vec::new();
+        for net_device in &*net_devices {
+            let net_device = net_device
+                .read()
+                .map_err(|_| anyhow::anyhow!("Failed to read lock"))?;
+            for interface in &net_device.interfaces {
+                interfaces.push((net_device.index, *interface));
             }
         }
+        Ok(interfaces)
+    }
+    /// Finds the interface owning the unicast `address` and the index of its device.
+    pub fn lookup_ip_interface(&self, address: u32) -> Result<Option<(u32, IPInterface)>> {
+        Ok(self
+            .all_ip_interfaces()?
+            .into_iter()
+            .find(|(_, interface)| interface.unicast == address))
+    }
+    /// Transmits the IP packets of `data`, the fragments of one datagram, to
+    /// `next_hop` on the device, resolving the hardware address of the next hop
+    /// first if the device needs it.
+    pub fn transmit_ip(&self, index: u32, data: Vec<Vec<u8>>, next_hop: u32) -> Result<()> {
+        let (flags, broadcast) = self.with_net_device(index, |net_device| {
+            (
+                net_device.flags,
+                next_hop == IP_ADDRESS_BROADCAST
+                    || net_device
+                        .interfaces
+                        .iter()
+                        .any(|interface| interface.broadcast == next_hop),
+            )
+        })?;
+        if flags & NET_DEVICE_FLAG_NEED_ARP != 0 && !broadcast {
+            return self
+                .arp_context
+                .output(self, index, next_hop.to_be_bytes(), data);
+        }
+        let destination: &[u8] = if flags & NET_DEVICE_FLAG_NEED_ARP != 0 {
+            &ETHERNET_ADDRESS_BROADCAST
+        } else {
+            &[]
+        };
+        for data in data {
+            self.transmit(index, NET_PROTOCOL_IP, data, destination)?;
+        }
+        Ok(())
+    }
+    pub fn arp_context(&self) -> &ARPEthernetIPContext {
+        &self.arp_context
+    }
+    pub fn ip_controller(&self) -> &IPController {
+        &self.ip_controller
+    }
+    pub fn icmp_controller(&self) -> &ICMPController {
+        &self.icmp_controller
+    }
+    pub fn mtu(&self, index: u32) -> Result<u16> {
+        self.with_net_device(index, |net_device| net_device.mtu())
+    }
+    pub fn hardware_address(&self, index: u32) -> Result<Vec<u8>> {
+        self.with_net_device(index, |net_device| {
+            net_device.net_driver.hardware_address().to_vec()
+        })
+    }
+    fn with_net_device<R>(&self, index: u32, f: impl FnOnce(&NetDevice) -> R) -> Result<R> {
+        let net_devices = self
+            .net_devices
+            .read()
+            .map_err(|_| anyhow::anyhow!("Failed to read lock"))?;
+        let net_device = net_devices
+            .get(index as usize)
+            .ok_or_else(|| anyhow::anyhow!("no such device, index={}", index))?
+            .read()
+            .map_err(|_| anyhow::anyhow!("Failed to read lock"))?;
+        Ok(f(&net_device))
+    }
+}
+
+/// A link-layer driver plugged into a `NetDevice`.
+///
+/// Drivers only move frames in and out of the medium; the `NetDevice` wrapping
+/// them takes care of the up/down state, the MTU check and the IRQ dispatch.
+pub trait NetDriver: Debug + Send + Sync {
+    /// IRQ number raised by the driver when frames are ready to be polled, `None`
+    /// for a driver taking any free one.
+    fn irq(&self) -> Option<i32>;
+    /// Receives the IRQ of the driver on registration.
+    fn set_irq(&mut self, _irq: i32) {}
+    fn mtu(&self) -> u16;
+    /// Capability flags of the driver (`NET_DEVICE_FLAG_*`, except `UP`).
+    fn flags(&self) -> u16 {
+        0
+    }
+    fn hardware_address(&self) -> &[u8] {
+        &[]
+    }
+    fn open(&mut self) -> Result<()> {
+        Ok(())
+    }
+    fn close(&mut self) -> Result<()> {
         Ok(())
     }
+    /// `destination` is the link-layer address of the next hop, empty for drivers without one.
+    fn transmit(&mut self, net_protocol_type: u16, data: Vec<u8>, destination: &[u8])
+        -> Result<()>;
+    /// Drains the frames received since the last call.
+    fn poll(&mut self) -> Result<Vec<NetDeviceQueueEntry>>;
 }
 
 struct NetDevice {
+    index: u32,
     name: String,
-    net_device_type: NetDeviceType,
+    net_driver: Box<dyn NetDriver>,
     net_device_context: Arc<NetDeviceContext>,
     flags: u16,
+    interfaces: Vec<IPInterface>,
 }
 impl NetDevice {
-    const FLAG_UP: u16 = 0x0001;
-
     pub fn new(
+        index: u32,
         name: String,
-        net_device_type: NetDeviceType,
+        net_driver: Box<dyn NetDriver>,
         net_device_context: Arc<NetDeviceContext>,
     ) -> NetDevice {
+        let flags = net_driver.flags() & !NET_DEVICE_FLAG_UP;
         NetDevice {
+            index,
             name,
-            net_device_type,
+            net_driver,
             net_device_context,
-            flags: 0,
+            flags,
+            interfaces: Vec::new(),
         }
     }
     pub fn open(&mut self) -> Result<()> {
@@ -241,11 +551,8 @@ impl NetDevice {
             error!("already opened, dev={}", self.name);
             return Err(anyhow::anyhow!("already opened"));
         }
-        match &self.net_device_type {
-            NetDeviceType::Dummy => {}
-            NetDeviceType::Loopback(_) => {}
-        }
-        self.flags |= Self::FLAG_UP;
+        self.net_driver.open()?;
+        self.flags |= NET_DEVICE_FLAG_UP;
         info!("dev={}, state={}", self.name, self.state());
         Ok(())
     }
@@ -254,15 +561,17 @@ impl NetDevice {
             error!("not opend, dev={}", self.name);
             return Err(anyhow::anyhow!("not opened"));
         }
-        match &self.net_device_type {
-            NetDeviceType::Dummy => {}
-            NetDeviceType::Loopback(_) => {}
-        }
-        self.flags &= !Self::FLAG_UP;
+        self.net_driver.close()?;
+        self.flags &= !NET_DEVICE_FLAG_UP;
         info!("dev={}, state={}", self.name, self.state());
         Ok(())
     }
-    pub fn transmit(&mut self, net_protocol_type: u16, data: String) -> Result<()> {
+    pub fn transmit(
+        &mut self,
+        net_protocol_type: u16,
+        data: Vec<u8>,
+        destination: &[u8],
+    ) -> Result<()> {
         if !self.is_up() {
             error!("not opened, dev={}", self.name);
             return Err(anyhow::anyhow!("not opened"));
@@ -277,67 +586,35 @@ impl NetDevice {
             return Err(anyhow::anyhow!("too long"));
         }
         debug!(
-            "dev={}, type={:?}, len={}",
+            "dev={}, driver={:?}, len={}",
             self.name,
-            self.net_device_type,
+            self.net_driver,
             data.len()
         );
-        debug!("data={}", data);
-        match &self.net_device_type {
-            NetDeviceType::Dummy => raise_irq(DUMMY_IRQ)?,
-            NetDeviceType::Loopback(net_device) => {
-                let mut queue = net_device
-                    .queue
-                    .lock()
-                    .map_err(|_| anyhow::anyhow!("Failed to lock"))?;
-                queue.push(LoopbackNetDeviceQueueEntry {
-                    net_protocol_type,
-                    data,
-                });
-                debug!(
-                    "queue pushed (num:{}), dev={}, type={:?}",
-                    queue.len(),
-                    self.name,
-                    self.net_device_type,
-                );
-                raise_irq(LOOPBACK_IRQ)?
-            }
-        }
-        Ok(())
+        debug!("data={:02x?}", data);
+        self.net_driver
+            .transmit(net_protocol_type, data, destination)
     }
     pub fn isr(&mut self, irq: i32) -> Result<()> {
         debug!("dev={}, irq={}", self.name, irq);
-        match &self.net_device_type {
-            NetDeviceType::Dummy => {}
-            NetDeviceType::Loopback(net_device) => {
-                let mut queue = net_device
-                    .queue
-                    .lock()
-                    .map_err(|_| anyhow::anyhow!("Failed to lock"))?;
-                while let Some(entry) = queue.pop() {
-                    debug!(
-                        "queue popped (num:{}), dev={}, type={:?}, len={}",
-                        queue.len(),
-                        self.name,
-                        entry.net_protocol_type,
-                        entry.data.len()
-                    );
-                    debug!("data={}", entry.data);
-                    self.net_device_context
-                        .input(entry.net_protocol_type, entry.data)?;
-                }
-            }
+        for entry in self.net_driver.poll()? {
+            debug!(
+                "polled, dev={}, type={}, len={}",
+                self.name,
+                entry.net_protocol_type,
+                entry.data.len()
+            );
+            debug!("data={:02x?}", entry.data);
+            self.net_device_context
+                .input(self.index, entry.net_protocol_type, entry.data)?;
         }
         Ok(())
     }
     fn mtu(&self) -> u16 {
-        match &self.net_device_type {
-            NetDeviceType::Dummy => u16::MAX,
-            NetDeviceType::Loopback(_) => u16::MAX,
-        }
+        self.net_driver.mtu()
     }
     fn is_up(&self) -> bool {
-        self.flags & Self::FLAG_UP != 0
+        self.flags & NET_DEVICE_FLAG_UP != 0
     }
     fn state(&self) -> String {
         if self.is_up() {
@@ -348,15 +625,37 @@ impl NetDevice {
     }
 }
 
-#[derive(Debug)]
-pub enum NetDeviceType {
-    Dummy,
-    Loopback(LoopbackNetDevice),
+#[derive(Debug, Default)]
+pub struct DummyNetDevice;
+impl DummyNetDevice {
+    pub fn new() -> DummyNetDevice {
+        Self
+    }
+}
+impl NetDriver for DummyNetDevice {
+    fn irq(&self) -> Option<i32> {
+        Some(DUMMY_IRQ)
+    }
+    fn mtu(&self) -> u16 {
+        u16::MAX
+    }
+    fn transmit(
+        &mut self,
+        _net_protocol_type: u16,
+        _data: Vec<u8>,
+        _destination: &[u8],
+    ) -> Result<()> {
+        // drop the data
+        raise_irq(DUMMY_IRQ)
+    }
+    fn poll(&mut self) -> Result<Vec<NetDeviceQueueEntry>> {
+        Ok(Vec::new())
+    }
 }
 
 #[derive(Debug)]
 pub struct LoopbackNetDevice {
-    queue: Mutex<Vec<LoopbackNetDeviceQueueEntry>>,
+    queue: Mutex<Vec<NetDeviceQueueEntry>>,
 }
 impl Default for LoopbackNetDevice {
     fn default() -> Self {
@@ -370,13 +669,398 @@ impl LoopbackNetDevice {
         Self::default()
     }
 }
+impl NetDriver for LoopbackNetDevice {
+    fn irq(&self) -> Option<i32> {
+        Some(LOOPBACK_IRQ)
+    }
+    fn mtu(&self) -> u16 {
+        u16::MAX
+    }
+    fn flags(&self) -> u16 {
+        NET_DEVICE_FLAG_LOOPBACK
+    }
+    fn transmit(
+        &mut self,
+        net_protocol_type: u16,
+        data: Vec<u8>,
+        _destination: &[u8],
+    ) -> Result<()> {
+        let mut queue = self
+            .queue
+            .lock()
+            .map_err(|_| anyhow::anyhow!("Failed to lock"))?;
+        queue.push(NetDeviceQueueEntry {
+            net_protocol_type,
+            data,
+        });
+        debug!("queue pushed (num:{})", queue.len());
+        raise_irq(LOOPBACK_IRQ)
+    }
+    fn poll(&mut self) -> Result<Vec<NetDeviceQueueEntry>> {
+        let mut queue = self
+            .queue
+            .lock()
+            .map_err(|_| anyhow::anyhow!("Failed to lock"))?;
+        let mut entries = Vec::new();
+        while let Some(entry) = queue.pop() {
+            debug!("queue popped (num:{})", queue.len());
+            entries.push(entry);
+        }
+        Ok(entries)
+    }
+}
+
+const TAP_DEVICE_PATH: &str = "/dev/net/tun";
+const TAP_POLL_TIMEOUT_MILLISECONDS: i32 = 100;
+const TUNSETIFF: libc::c_ulong = 0x400454ca;
+
+/// Ethernet device backed by a Linux TAP interface.
+///
+/// The interface has to exist on the host beforehand, e.g.
+/// `ip tuntap add mode tap user $USER name tap0 && ip link set tap0 up`.
 #[derive(Debug)]
-struct LoopbackNetDeviceQueueEntry {
-    net_protocol_type: u16,
-    data: String,
+pub struct TapNetDevice {
+    name: String,
+    /// Given on registration.
+    irq: Option<i32>,
+    hardware_address: [u8; 6],
+    file: Option<Arc<File>>,
+    queue: Arc<Mutex<Vec<Vec<u8>>>>,
+    running: Arc<AtomicBool>,
+    reader: Option<JoinHandle<()>>,
+}
+impl TapNetDevice {
+    pub fn new(name: &str, hardware_address: [u8; 6]) -> TapNetDevice {
+        TapNetDevice {
+            name: name.to_string(),
+            irq: None,
+            hardware_address,
+            file: None,
+            queue: Arc::new(Mutex::new(Vec::new())),
+            running: Arc::new(AtomicBool::new(false)),
+            reader: None,
+        }
+    }
+    fn attach(&self) -> Result<File> {
+        if self.name.len() >= libc::IFNAMSIZ {
+            return Err(anyhow::anyhow!("too long tap name, name={}", self.name));
+        }
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(TAP_DEVICE_PATH)?;
+        let mut ifr: libc::ifreq = unsafe { std::mem::zeroed() };
+        for (dst, src) in ifr.ifr_name.iter_mut().zip(self.name.bytes()) {
+            *dst = src as libc::c_char;
+        }
+        ifr.ifr_ifru.ifru_flags = (libc::IFF_TAP | libc::IFF_NO_PI) as libc::c_short;
+        if unsafe { libc::ioctl(file.as_raw_fd(), TUNSETIFF as _, &mut ifr) } == -1 {
+            return Err(anyhow::anyhow!(
+                "ioctl(TUNSETIFF) failed, name={}, err={}",
+                self.name,
+                std::io::Error::last_os_error()
+            ));
+        }
+        Ok(file)
+    }
+    fn read_loop(
+        file: Arc<File>,
+        queue: Arc<Mutex<Vec<Vec<u8>>>>,
+        running: Arc<AtomicBool>,
+        irq: i32,
+    ) {
+        let mut buf = [0u8; ETHERNET_FRAME_SIZE_MAX];
+        while running.load(std::sync::atomic::Ordering::SeqCst) {
+            let mut pollfd = libc::pollfd {
+                fd: file.as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            };
+            let ret = unsafe { libc::poll(&mut pollfd, 1, TAP_POLL_TIMEOUT_MILLISECONDS) };
+            if ret == -1 {
+                let err = std::io::Error::last_os_error();
+                if err.kind() == std::io::ErrorKind::Interrupted {
+                    continue;
+                }
+                error!("poll failed, err={}", err);
+                break;
+            }
+            if ret == 0 {
+                continue;
+            }
+            let len = match (&*file).read(&mut buf) {
+                Ok(len) => len,
+                Err(e) => {
+                    error!("read failed, err={}", e);
+                    break;
+                }
+            };
+            match queue.lock() {
+                Ok(mut queue) => queue.push(buf[..len].to_vec()),
+                Err(_) => {
+                    error!("Failed to lock");
+                    break;
+                }
+            }
+            if let Err(e) = raise_irq(irq) {
+                error!("raise irq failed, err={}", e);
+            }
+        }
+        debug!("tap reader terminated");
+    }
+}
+impl NetDriver for TapNetDevice {
+    fn irq(&self) -> Option<i32> {
+        self.irq
+    }
+    fn set_irq(&mut self, irq: i32) {
+        self.irq = Some(irq);
+    }
+    fn mtu(&self) -> u16 {
+        ETHERNET_PAYLOAD_SIZE_MAX as u16
+    }
+    fn flags(&self) -> u16 {
+        NET_DEVICE_FLAG_BROADCAST | NET_DEVICE_FLAG_NEED_ARP
+    }
+    fn hardware_address(&self) -> &[u8] {
+        &self.hardware_address
+    }
+    fn open(&mut self) -> Result<()> {
+        let irq = self
+            .irq
+            .ok_or_else(|| anyhow::anyhow!("tap not registered, name={}", self.name))?;
+        let file = Arc::new(self.attach()?);
+        self.running
+            .store(true, std::sync::atomic::Ordering::SeqCst);
+        let file_clone = file.clone();
+        let queue_clone = self.queue.clone();
+        let running_clone = self.running.clone();
+        self.reader = Some(thread::spawn(move || {
+            Self::read_loop(file_clone, queue_clone, running_clone, irq)
+        }));
+        self.file = Some(file);
+        info!("tap attached, name={}", self.name);
+        Ok(())
+    }
+    fn close(&mut self) -> Result<()> {
+        self.running
+            .store(false, std::sync::atomic::Ordering::SeqCst);
+        if let Some(reader) = self.reader.take() {
+            reader
+                .join()
+                .map_err(|_| anyhow::anyhow!("Failed to join tap reader"))?;
+        }
+        self.file = None;
+        Ok(())
+    }
+    fn transmit(
+        &mut self,
+        net_protocol_type: u16,
+        data: Vec<u8>,
+        destination: &[u8],
+    ) -> Result<()> {
+        let file = self
+            .file
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("tap not attached, name={}", self.name))?;
+        let frame =
+            ethernet::transmit_helper(self.hardware_address, destination, net_protocol_type, data)?;
+        (&**file).write_all(&frame)?;
+        Ok(())
+    }
+    fn poll(&mut self) -> Result<Vec<NetDeviceQueueEntry>> {
+        let frames: Vec<Vec<u8>> = self
+            .queue
+            .lock()
+            .map_err(|_| anyhow::anyhow!("Failed to lock"))?
+            .drain(..)
+            .collect();
+        let mut entries = Vec::new();
+        for frame in frames {
+            match ethernet::input_helper(self.hardware_address, &frame) {
+                Ok(Some(entry)) => entries.push(entry),
+                Ok(None) => {}
+                Err(e) => debug!("dropped, name={}, err={}", self.name, e),
+            }
+        }
+        Ok(entries)
+    }
+}
+
+#[derive(Debug)]
+pub struct NetDeviceQueueEntry {
+    pub net_protocol_type: u16,
+    pub data: Vec<u8>,
+}
+
+pub type NetTimerHandler = Box<dyn Fn(&NetDeviceContext) -> Result<()> + Send + Sync>;
+
+struct NetTimer {
+    interval: Duration,
+    last: Mutex<Instant>,
+    handler: NetTimerHandler,
 }
 
 struct NetProtocol {
     protocol_type: u16,
-    queue: Mutex<Vec<String>>,
+    queue: Mutex<Vec<NetProtocolQueueEntry>>,
+}
+struct NetProtocolQueueEntry {
+    device_index: u32,
+    data: Vec<u8>,
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::ip::IP_ADDRESS_ANY;
+
+    /// Packets transmitted by a `CaptureNetDevice`, oldest first.
+    pub(crate) type Captured = Arc<Mutex<Vec<Vec<u8>>>>;
+
+    /// Driver keeping the packets it is asked to transmit. With a hardware
+    /// address, it resolves next hops with ARP like an Ethernet device.
+    #[derive(Debug)]
+    pub(crate) struct CaptureNetDevice {
+        sent: Captured,
+        hardware_address: Vec<u8>,
+    }
+    impl NetDriver for CaptureNetDevice {
+        fn irq(&self) -> Option<i32> {
+            None
+        }
+        fn mtu(&self) -> u16 {
+            ETHERNET_PAYLOAD_SIZE_MAX as u16
+        }
+        fn flags(&self) -> u16 {
+            if self.hardware_address.is_empty() {
+                return 0;
+            }
+            NET_DEVICE_FLAG_BROADCAST | NET_DEVICE_FLAG_NEED_ARP
+        }
+        fn hardware_address(&self) -> &[u8] {
+            &self.hardware_address
+        }
+        fn transmit(
+            &mut self,
+            _net_protocol_type: u16,
+            data: Vec<u8>,
+            _destination: &[u8],
+        ) -> Result<()> {
+            self.sent
+                .lock()
+                .map_err(|_| anyhow::anyhow!("Failed to lock"))?
+                .push(data);
+            Ok(())
+        }
+        fn poll(&mut self) -> Result<Vec<NetDeviceQueueEntry>> {
+            Ok(Vec::new())
+        }
+    }
+
+    /// A context with a capture device for each of `interfaces`, opened without
+    /// running the IRQ thread, along with what each device transmits.
+    pub(crate) fn context(interfaces: &[&str]) -> (Arc<NetDeviceContext>, Vec<Captured>) {
+        build_context(interfaces, &[])
+    }
+
+    /// Like `context`, with devices resolving next hops with ARP, the device of
+    /// index `i` having `ETHERNET_ADDRESS` with `i` as its last byte.
+    pub(crate) fn ethernet_context(interfaces: &[&str]) -> (Arc<NetDeviceContext>, Vec<Captured>) {
+        build_context(interfaces, &ETHERNET_ADDRESS)
+    }
+
+    pub(crate) const ETHERNET_ADDRESS: [u8; 6] = [0x02, 0, 0, 0, 0, 0];
+
+    fn build_context(
+        interfaces: &[&str],
+        hardware_address: &[u8],
+    ) -> (Arc<NetDeviceContext>, Vec<Captured>) {
+        let context = NetDeviceContext::new().unwrap();
+        let mut sent = Vec::new();
+        for (index, interface) in interfaces.iter().enumerate() {
+            let device_sent = Arc::new(Mutex::new(Vec::new()));
+            let mut hardware_address = hardware_address.to_vec();
+            if let Some(last) = hardware_address.last_mut() {
+                *last = index as u8;
+            }
+            let driver = CaptureNetDevice {
+                sent: device_sent.clone(),
+                hardware_address,
+            };
+            context.register(Box::new(driver), context.clone()).unwrap();
+            context
+                .add_ip_interface(index as u32, IPInterface::parse(interface).unwrap())
+                .unwrap();
+            sent.push(device_sent);
+        }
+        for net_device in &*context.net_devices.read().unwrap() {
+            net_device.write().unwrap().open().unwrap();
+        }
+        (context, sent)
+    }
+
+    #[test]
+    fn add_ip_interface_shares_connected_route() {
+        let (context, _) = context(&["192.0.2.1/24"]);
+        context
+            .add_ip_interface(0, IPInterface::parse("192.0.2.2/24").unwrap())
+            .unwrap();
+        assert_eq!(context.ip_interfaces(0).unwrap().len(), 2);
+        assert_eq!(context.ip_controller().routes().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn add_ip_interface_keeps_nothing_on_error() {
+        let (context, _) = context(&["192.0.2.1/24"]);
+        let interface = IPInterface::parse("198.51.100.1/24").unwrap();
+        context
+            .ip_controller()
+            .add_route(
+                interface.network(),
+                interface.netmask,
+                IP_ADDRESS_ANY,
+                Some(0),
+                0,
+            )
+            .unwrap();
+        assert!(context.add_ip_interface(0, interface).is_err());
+        assert_eq!(context.ip_interfaces(0).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn remove_ip_interface_keeps_route_of_remaining_address() {
+        let (context, _) = context(&["192.0.2.1/24"]);
+        context
+            .add_ip_interface(0, IPInterface::parse("192.0.2.2/24").unwrap())
+            .unwrap();
+        context.remove_ip_interface(0, 0xc0000201).unwrap();
+        assert_eq!(context.ip_controller().routes().unwrap().len(), 1);
+        assert!(context
+            .ip_controller()
+            .lookup_route(0xc0000263)
+            .unwrap()
+            .is_some());
+        context.remove_ip_interface(0, 0xc0000202).unwrap();
+        assert!(context.ip_controller().routes().unwrap().is_empty());
+    }
+
+    #[test]
+    fn register_fails_once_irqs_are_used_up() {
+        let (context, _) = context(&[]);
+        let capture = || {
+            Box::new(CaptureNetDevice {
+                sent: Arc::new(Mutex::new(Vec::new())),
+                hardware_address: Vec::new(),
+            })
+        };
+        for _ in DYNAMIC_IRQ_MIN..IRQContext::AVAILABLE_IRQ_MAX {
+            context.register(capture(), context.clone()).unwrap();
+        }
+        assert!(context.register(capture(), context.clone()).is_err());
+        // The fixed IRQ of the loopback is still free.
+        context
+            .register(Box::new(LoopbackNetDevice::new()), context.clone())
+            .unwrap();
+    }
 }
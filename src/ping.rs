@@ -0,0 +1,435 @@
+use std::{
+    collections::HashMap,
+    net::Ipv4Addr,
+    sync::{
+        atomic::{AtomicU16, Ordering},
+        mpsc,
+    },
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use log::debug;
+
+use crate::arp::ARPError;
+use crate::icmp::{
+    ICMPMessage, SocketError, ICMP_CODE_HOST_UNREACHABLE, ICMP_HEADER_LENGTH,
+    ICMP_TYPE_DEST_UNREACHABLE,
+};
+use crate::ip::{
+    IPOutputOptions, IPProtocol, IP_ADDRESS_ANY, IP_HEADER_LENGTH_MIN, IP_TTL_DEFAULT,
+};
+use crate::net::NetDeviceContext;
+
+pub const PING_SIZE_DEFAULT: usize = 56;
+static PING_IDENTIFIER_NEXT: AtomicU16 = AtomicU16::new(0);
+
+/// Settings of `ping`, the defaults matching the `ping` command.
+#[derive(Debug, Clone)]
+pub struct PingOptions {
+    /// Bytes of data after the ICMP header.
+    pub size: usize,
+    pub count: u16,
+    /// Delay between two requests.
+    pub interval: Duration,
+    pub ttl: u8,
+    /// Time after which a request without reply is counted as lost.
+    pub timeout: Duration,
+    /// Source address, `IP_ADDRESS_ANY` to let the routing table choose.
+    pub source: u32,
+}
+
+impl Default for PingOptions {
+    fn default() -> Self {
+        PingOptions {
+            size: PING_SIZE_DEFAULT,
+            count: 4,
+            interval: Duration::from_secs(1),
+            ttl: IP_TTL_DEFAULT,
+            timeout: Duration::from_secs(2),
+            source: IP_ADDRESS_ANY,
+        }
+    }
+}
+
+/// Outcome of one Echo Request.
+#[derive(Debug, Clone, PartialEq)]
+pub enum PingResult {
+    Reply {
+        sequence: u16,
+        from: u32,
+        /// Bytes of data of the reply after the ICMP header.
+        size: usize,
+        ttl: u8,
+        rtt: Duration,
+    },
+    /// An ICMP error about the request came back from `from`.
+    Error {
+        sequence: u16,
+        from: u32,
+        icmp_type: u8,
+        code: u8,
+        error: SocketError,
+    },
+    Timeout {
+        sequence: u16,
+    },
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PingStatistics {
+    pub transmitted: u32,
+    pub received: u32,
+    pub errors: u32,
+    pub rtt_min: Option<Duration>,
+    pub rtt_avg: Option<Duration>,
+    pub rtt_max: Option<Duration>,
+    /// Standard deviation of the round-trip times.
+    pub rtt_mdev: Option<Duration>,
+}
+
+impl PingStatistics {
+    /// Percentage of the requests left without reply.
+    pub fn loss(&self) -> f64 {
+        if self.transmitted == 0 {
+            return 0.0;
+        }
+        (self.transmitted - self.received) as f64 * 100.0 / self.transmitted as f64
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PingReport {
+    pub results: Vec<PingResult>,
+    pub statistics: PingStatistics,
+}
+
+/// What the ICMP handlers pass on to the loop of `ping`.
+enum PingEvent {
+    Reply {
+        sequence: u16,
+        from: u32,
+        size: usize,
+        ttl: u8,
+        received: Instant,
+    },
+    Error {
+        sequence: u16,
+        from: u32,
+        icmp_type: u8,
+        code: u8,
+        error: SocketError,
+    },
+}
+
+/// Sends `options.count` Echo Requests to `destination` and waits for their
+/// replies, calling `on_result` as each request completes.
+///
+/// Fails when the first request cannot be sent. A later request which cannot be
+/// sent counts as lost, or as a Host Unreachable when the resolution of the next
+/// hop failed.
+///
+/// The context has to be running, since replies are received by its IRQ thread.
+pub fn ping(
+    context: &NetDeviceContext,
+    destination: u32,
+    options: &PingOptions,
+    mut on_result: impl FnMut(&PingResult),
+) -> Result<PingReport> {
+    let identifier = identifier();
+    let (sender, receiver) = mpsc::channel();
+    let icmp = context.icmp_controller();
+    let reply_sender = sender.clone();
+    icmp.register_echo_handler(
+        identifier,
+        Box::new(move |_, packet, message| {
+            // Another run may share the identifier with another destination.
+            if packet.header().source_ip_address() != destination {
+                return Ok(());
+            }
+            let event = PingEvent::Reply {
+                sequence: message.sequence(),
+                from: packet.header().source_ip_address(),
+                size: message.data().len(),
+                ttl: packet.header().ttl(),
+                received: Instant::now(),
+            };
+            reply_sender.send(event).ok();
+            Ok(())
+        }),
+    )?;
+    let error_sender = sender;
+    if let Err(e) = icmp.register_endpoint(
+        IPProtocol::ICMP,
+        IP_ADDRESS_ANY,
+        identifier,
+        Box::new(move |_, report| {
+            // Another run may share the identifier with another destination.
+            if report.remote_address != destination {
+                return Ok(());
+            }
+            let event = PingEvent::Error {
+                sequence: u16::from_be_bytes([report.data[6], report.data[7]]),
+                from: report.offender,
+                icmp_type: report.icmp_type,
+                code: report.code,
+                error: report.error,
+            };
+            error_sender.send(event).ok();
+            Ok(())
+        }),
+    ) {
+        icmp.unregister_echo_handler(identifier)?;
+        return Err(e);
+    }
+    let report = run(
+        context,
+        destination,
+        options,
+        identifier,
+        &receiver,
+        &mut on_result,
+    );
+    icmp.unregister_echo_handler(identifier)?;
+    icmp.unregister_endpoint(IPProtocol::ICMP, IP_ADDRESS_ANY, identifier)?;
+    report
+}
+
+fn run(
+    context: &NetDeviceContext,
+    destination: u32,
+    options: &PingOptions,
+    identifier: u16,
+    receiver: &mpsc::Receiver<PingEvent>,
+    on_result: &mut impl FnMut(&PingResult),
+) -> Result<PingReport> {
+    let payload: Vec<u8> = (0..options.size).map(|i| i as u8).collect();
+    let mut outstanding: HashMap<u16, Instant> = HashMap::new();
+    let mut results = Vec::new();
+    let mut complete = |result: PingResult, results: &mut Vec<PingResult>| {
+        on_result(&result);
+        results.push(result);
+    };
+    debug!(
+        "ping, dst={}, identifier={}, size={}",
+        Ipv4Addr::from(destination),
+        identifier,
+        options.size
+    );
+    for sequence in 0..options.count {
+        let message = ICMPMessage::echo_request(identifier, sequence, payload.clone());
+        let sent = context.ip_controller().output_with(
+            context,
+            IPProtocol::ICMP,
+            message.serialize(),
+            options.source,
+            destination,
+            IPOutputOptions {
+                ttl: options.ttl,
+                ..Default::default()
+            },
+        );
+        match sent {
+            Ok(()) => {
+                outstanding.insert(sequence, Instant::now());
+            }
+            // The request was dropped, the resolution of the next hop having failed.
+            Err(e) if e.downcast_ref::<ARPError>() == Some(&ARPError::Unreachable) => {
+                let from = match options.source {
+                    IP_ADDRESS_ANY => context
+                        .ip_controller()
+                        .source_address(context, destination)?,
+                    source => source,
+                };
+                let result = PingResult::Error {
+                    sequence,
+                    from,
+                    icmp_type: ICMP_TYPE_DEST_UNREACHABLE,
+                    code: ICMP_CODE_HOST_UNREACHABLE,
+                    error: SocketError::HostUnreachable,
+                };
+                complete(result, &mut results);
+            }
+            Err(e) if sequence == 0 => return Err(e),
+            // Like a request without reply, the failure counts as a loss.
+            Err(e) => debug!("request not sent, seq={}, err={}", sequence, e),
+        }
+        let deadline = if sequence + 1 == options.count {
+            Instant::now() + options.timeout
+        } else {
+            Instant::now() + options.interval
+        };
+        loop {
+            let now = Instant::now();
+            let mut expired: Vec<u16> = outstanding
+                .iter()
+                .filter(|(_, sent)| now.duration_since(**sent) >= options.timeout)
+                .map(|(sequence, _)| *sequence)
+                .collect();
+            expired.sort_unstable();
+            for sequence in expired {
+                outstanding.remove(&sequence);
+                complete(PingResult::Timeout { sequence }, &mut results);
+            }
+            if now >= deadline || (sequence + 1 == options.count && outstanding.is_empty()) {
+                break;
+            }
+            let event = match receiver.recv_timeout(deadline - now) {
+                Ok(event) => event,
+                Err(mpsc::RecvTimeoutError::Timeout) => continue,
+                Err(mpsc::RecvTimeoutError::Disconnected) => {
+                    return Err(anyhow::anyhow!("ping handlers unregistered"))
+                }
+            };
+            let result = match event {
+                PingEvent::Reply {
+                    sequence,
+                    from,
+                    size,
+                    ttl,
+                    received,
+                } => match outstanding.remove(&sequence) {
+                    Some(sent) => PingResult::Reply {
+                        sequence,
+                        from,
+                        size,
+                        ttl,
+                        rtt: received.duration_since(sent),
+                    },
+                    None => continue,
+                },
+                PingEvent::Error {
+                    sequence,
+                    from,
+                    icmp_type,
+                    code,
+                    error,
+                } => match outstanding.remove(&sequence) {
+                    Some(_) => PingResult::Error {
+                        sequence,
+                        from,
+                        icmp_type,
+                        code,
+                        error,
+                    },
+                    None => continue,
+                },
+            };
+            complete(result, &mut results);
+        }
+    }
+    let statistics = statistics(options.count, &results);
+    Ok(PingReport {
+        results,
+        statistics,
+    })
+}
+
+fn statistics(transmitted: u16, results: &[PingResult]) -> PingStatistics {
+    let rtts: Vec<f64> = results
+        .iter()
+        .filter_map(|result| match result {
+            PingResult::Reply { rtt, .. } => Some(rtt.as_secs_f64()),
+            _ => None,
+        })
+        .collect();
+    let errors = results
+        .iter()
+        .filter(|result| matches!(result, PingResult::Error { .. }))
+        .count();
+    let mut statistics = PingStatistics {
+        transmitted: transmitted as u32,
+        received: rtts.len() as u32,
+        errors: errors as u32,
+        ..Default::default()
+    };
+    if !rtts.is_empty() {
+        let avg = rtts.iter().sum::<f64>() / rtts.len() as f64;
+        let variance = rtts.iter().map(|rtt| (rtt - avg).powi(2)).sum::<f64>() / rtts.len() as f64;
+        statistics.rtt_min = Some(Duration::from_secs_f64(
+            rtts.iter().copied().fold(f64::MAX, f64::min),
+        ));
+        statistics.rtt_avg = Some(Duration::from_secs_f64(avg));
+        statistics.rtt_max = Some(Duration::from_secs_f64(
+            rtts.iter().copied().fold(0.0, f64::max),
+        ));
+        statistics.rtt_mdev = Some(Duration::from_secs_f64(variance.sqrt()));
+    }
+    statistics
+}
+
+/// A fresh identifier for the probes of one run, told apart from the other
+/// runs of this process and, as far as 16 bits allow, of other processes.
+pub(crate) fn identifier() -> u16 {
+    (std::process::id() as u16).wrapping_add(PING_IDENTIFIER_NEXT.fetch_add(1, Ordering::Relaxed))
+}
+
+/// Length of the IP datagram carrying an Echo Request of `size` bytes of data.
+pub fn datagram_length(size: usize) -> usize {
+    IP_HEADER_LENGTH_MIN + ICMP_HEADER_LENGTH + size
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reply(sequence: u16, rtt: u64) -> PingResult {
+        PingResult::Reply {
+            sequence,
+            from: 0xc0000202,
+            size: PING_SIZE_DEFAULT,
+            ttl: IP_TTL_DEFAULT,
+            rtt: Duration::from_millis(rtt),
+        }
+    }
+
+    #[test]
+    fn statistics_of_replies() {
+        let results = [
+            reply(0, 10),
+            reply(1, 20),
+            reply(2, 30),
+            PingResult::Timeout { sequence: 3 },
+        ];
+        let statistics = statistics(4, &results);
+        assert_eq!(statistics.transmitted, 4);
+        assert_eq!(statistics.received, 3);
+        assert_eq!(statistics.errors, 0);
+        assert_eq!(statistics.loss(), 25.0);
+        let millis =
+            |rtt: Option<Duration>| (rtt.unwrap().as_secs_f64() * 1000.0 * 1000.0).round() / 1000.0;
+        assert_eq!(millis(statistics.rtt_min), 10.0);
+        assert_eq!(millis(statistics.rtt_avg), 20.0);
+        assert_eq!(millis(statistics.rtt_max), 30.0);
+        // Population standard deviation, as `ping` reports it.
+        assert_eq!(millis(statistics.rtt_mdev), 8.165);
+    }
+
+    #[test]
+    fn statistics_count_errors_as_lost() {
+        let error = PingResult::Error {
+            sequence: 1,
+            from: 0xc0000201,
+            icmp_type: ICMP_TYPE_DEST_UNREACHABLE,
+            code: ICMP_CODE_HOST_UNREACHABLE,
+            error: SocketError::HostUnreachable,
+        };
+        let statistics = statistics(2, &[reply(0, 10), error]);
+        assert_eq!(statistics.received, 1);
+        assert_eq!(statistics.errors, 1);
+        assert_eq!(statistics.loss(), 50.0);
+        assert_eq!(statistics.rtt_mdev, Some(Duration::ZERO));
+    }
+
+    #[test]
+    fn statistics_without_replies() {
+        let statistics = statistics(2, &[PingResult::Timeout { sequence: 0 }]);
+        assert_eq!(statistics.received, 0);
+        assert_eq!(statistics.loss(), 100.0);
+        assert_eq!(statistics.rtt_min, None);
+        assert_eq!(statistics.rtt_avg, None);
+        assert_eq!(statistics.rtt_max, None);
+        assert_eq!(statistics.rtt_mdev, None);
+        assert_eq!(super::statistics(0, &[]).loss(), 0.0);
+    }
+}
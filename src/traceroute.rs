@@ -0,0 +1,332 @@
+use std::{
+    net::Ipv4Addr,
+    sync::mpsc,
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use log::debug;
+
+use crate::arp::ARPError;
+use crate::icmp::{
+    ICMPMessage, ICMP_CODE_HOST_UNREACHABLE, ICMP_HEADER_LENGTH, ICMP_TYPE_DEST_UNREACHABLE,
+    ICMP_TYPE_ECHO_REPLY, ICMP_TYPE_TIME_EXCEEDED,
+};
+use crate::ip::{IPOutputOptions, IPProtocol, IP_ADDRESS_ANY, IP_HEADER_LENGTH_MIN};
+use crate::net::NetDeviceContext;
+use crate::ping;
+
+/// First destination port of UDP probes, the one of the `traceroute` command.
+pub const TRACEROUTE_PORT_DEFAULT: u16 = 33434;
+pub const TRACEROUTE_SIZE_DEFAULT: usize = 32;
+const UDP_HEADER_LENGTH: usize = 8;
+
+/// What the probes are sent as.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TracerouteMethod {
+    /// Echo Requests, answered by the destination with an Echo Reply.
+    ICMP,
+    /// UDP datagrams to unlikely ports, answered by the destination with a
+    /// Port Unreachable.
+    UDP,
+}
+
+/// Settings of `traceroute`, the defaults matching the `traceroute` command.
+#[derive(Debug, Clone)]
+pub struct TracerouteOptions {
+    pub method: TracerouteMethod,
+    pub first_ttl: u8,
+    pub max_ttl: u8,
+    /// Probes sent with each TTL.
+    pub probes: u8,
+    /// Destination port of the first UDP probe, incremented for each probe.
+    pub port: u16,
+    /// Bytes of data after the UDP or ICMP header.
+    pub size: usize,
+    /// Time after which a probe without answer is counted as lost.
+    pub timeout: Duration,
+    /// Source address, `IP_ADDRESS_ANY` to let the routing table choose.
+    pub source: u32,
+}
+
+impl Default for TracerouteOptions {
+    fn default() -> Self {
+        TracerouteOptions {
+            method: TracerouteMethod::UDP,
+            first_ttl: 1,
+            max_ttl: 30,
+            probes: 3,
+            port: TRACEROUTE_PORT_DEFAULT,
+            size: TRACEROUTE_SIZE_DEFAULT,
+            timeout: Duration::from_secs(5),
+            source: IP_ADDRESS_ANY,
+        }
+    }
+}
+
+/// Answer to one probe.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TracerouteProbe {
+    pub from: u32,
+    pub icmp_type: u8,
+    pub code: u8,
+    pub rtt: Duration,
+}
+
+/// Probes sent with one TTL, `None` for those left without answer.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TracerouteHop {
+    pub ttl: u8,
+    pub probes: Vec<Option<TracerouteProbe>>,
+}
+
+impl TracerouteHop {
+    /// Whether a probe of this hop got anything but a Time Exceeded, meaning
+    /// that no further hop would answer.
+    pub fn is_last(&self) -> bool {
+        self.probes
+            .iter()
+            .flatten()
+            .any(|probe| probe.icmp_type != ICMP_TYPE_TIME_EXCEEDED)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TracerouteReport {
+    pub hops: Vec<TracerouteHop>,
+    /// Whether the destination itself answered.
+    pub reached: bool,
+}
+
+/// What the ICMP handlers pass on to the loop of `traceroute`.
+struct TracerouteEvent {
+    sequence: u16,
+    from: u32,
+    icmp_type: u8,
+    code: u8,
+    received: Instant,
+}
+
+/// Sends probes to `destination` with a TTL going from `options.first_ttl` up
+/// to `options.max_ttl`, calling `on_hop` as the probes of each TTL complete.
+///
+/// Stops after the hop where a probe got anything but a Time Exceeded. A probe
+/// which cannot be sent counts as lost, or as a Host Unreachable when the
+/// resolution of the next hop failed. The context has to be running, since
+/// answers are received by its IRQ thread.
+pub fn traceroute(
+    context: &NetDeviceContext,
+    destination: u32,
+    options: &TracerouteOptions,
+    mut on_hop: impl FnMut(&TracerouteHop),
+) -> Result<TracerouteReport> {
+    if options.first_ttl == 0 || options.first_ttl > options.max_ttl {
+        return Err(anyhow::anyhow!(
+            "invalid ttl range, first={}, max={}",
+            options.first_ttl,
+            options.max_ttl
+        ));
+    }
+    // The echo identifier or the UDP source port, errors being delivered by
+    // either through the endpoint of the local port.
+    let identifier = ping::identifier();
+    let (sender, receiver) = mpsc::channel();
+    let icmp = context.icmp_controller();
+    let protocol = match options.method {
+        TracerouteMethod::ICMP => {
+            let reply_sender = sender.clone();
+            icmp.register_echo_handler(
+                identifier,
+                Box::new(move |_, packet, message| {
+                    let event = TracerouteEvent {
+                        sequence: message.sequence(),
+                        from: packet.header().source_ip_address(),
+                        icmp_type: ICMP_TYPE_ECHO_REPLY,
+                        code: 0,
+                        received: Instant::now(),
+                    };
+                    reply_sender.send(event).ok();
+                    Ok(())
+                }),
+            )?;
+            IPProtocol::ICMP
+        }
+        TracerouteMethod::UDP => IPProtocol::UDP,
+    };
+    let port = options.port;
+    let error_sender = sender;
+    if let Err(e) = icmp.register_endpoint(
+        protocol,
+        IP_ADDRESS_ANY,
+        identifier,
+        Box::new(move |_, report| {
+            // Another run may share the port or identifier with another destination.
+            if report.remote_address != destination {
+                return Ok(());
+            }
+            let sequence = match report.protocol {
+                IPProtocol::ICMP => u16::from_be_bytes([report.data[6], report.data[7]]),
+                _ => report.remote_port.wrapping_sub(port),
+            };
+            let event = TracerouteEvent {
+                sequence,
+                from: report.offender,
+                icmp_type: report.icmp_type,
+                code: report.code,
+                received: Instant::now(),
+            };
+            error_sender.send(event).ok();
+            Ok(())
+        }),
+    ) {
+        if protocol == IPProtocol::ICMP {
+            icmp.unregister_echo_handler(identifier)?;
+        }
+        return Err(e);
+    }
+    let report = run(
+        context,
+        destination,
+        options,
+        identifier,
+        &receiver,
+        &mut on_hop,
+    );
+    if protocol == IPProtocol::ICMP {
+        icmp.unregister_echo_handler(identifier)?;
+    }
+    icmp.unregister_endpoint(protocol, IP_ADDRESS_ANY, identifier)?;
+    report
+}
+
+fn run(
+    context: &NetDeviceContext,
+    destination: u32,
+    options: &TracerouteOptions,
+    identifier: u16,
+    receiver: &mpsc::Receiver<TracerouteEvent>,
+    on_hop: &mut impl FnMut(&TracerouteHop),
+) -> Result<TracerouteReport> {
+    let payload: Vec<u8> = (0..options.size).map(|i| i as u8).collect();
+    let mut sequence: u16 = 0;
+    let mut hops = Vec::new();
+    let mut reached = false;
+    debug!(
+        "traceroute, dst={}, method={:?}, identifier={}",
+        Ipv4Addr::from(destination),
+        options.method,
+        identifier
+    );
+    for ttl in options.first_ttl..=options.max_ttl {
+        let mut hop = TracerouteHop {
+            ttl,
+            probes: Vec::new(),
+        };
+        for _ in 0..options.probes {
+            let (protocol, data) = match options.method {
+                TracerouteMethod::ICMP => (
+                    IPProtocol::ICMP,
+                    ICMPMessage::echo_request(identifier, sequence, payload.clone()).serialize(),
+                ),
+                TracerouteMethod::UDP => (
+                    IPProtocol::UDP,
+                    udp_probe(identifier, options.port.wrapping_add(sequence), &payload),
+                ),
+            };
+            let sent = Instant::now();
+            let result = context.ip_controller().output_with(
+                context,
+                protocol,
+                data,
+                options.source,
+                destination,
+                IPOutputOptions {
+                    ttl,
+                    ..Default::default()
+                },
+            );
+            let deadline = sent + options.timeout;
+            let probe = match result {
+                Ok(()) => loop {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        break None;
+                    }
+                    let event = match receiver.recv_timeout(deadline - now) {
+                        Ok(event) => event,
+                        Err(mpsc::RecvTimeoutError::Timeout) => break None,
+                        Err(mpsc::RecvTimeoutError::Disconnected) => {
+                            return Err(anyhow::anyhow!("traceroute handlers unregistered"))
+                        }
+                    };
+                    // Late answers to probes already counted as lost.
+                    if event.sequence != sequence {
+                        continue;
+                    }
+                    break Some(TracerouteProbe {
+                        from: event.from,
+                        icmp_type: event.icmp_type,
+                        code: event.code,
+                        rtt: event.received.duration_since(sent),
+                    });
+                },
+                // The probe was dropped, the resolution of the next hop having failed.
+                Err(e) if e.downcast_ref::<ARPError>() == Some(&ARPError::Unreachable) => {
+                    let from = match options.source {
+                        IP_ADDRESS_ANY => context
+                            .ip_controller()
+                            .source_address(context, destination)?,
+                        source => source,
+                    };
+                    Some(TracerouteProbe {
+                        from,
+                        icmp_type: ICMP_TYPE_DEST_UNREACHABLE,
+                        code: ICMP_CODE_HOST_UNREACHABLE,
+                        rtt: sent.elapsed(),
+                    })
+                }
+                // Like a probe without answer, the failure counts as a loss.
+                Err(e) => {
+                    debug!("probe not sent, ttl={}, seq={}, err={}", ttl, sequence, e);
+                    None
+                }
+            };
+            if probe
+                .as_ref()
+                .is_some_and(|probe| probe.from == destination)
+            {
+                reached = true;
+            }
+            hop.probes.push(probe);
+            sequence = sequence.wrapping_add(1);
+        }
+        on_hop(&hop);
+        let last = hop.is_last();
+        hops.push(hop);
+        if last {
+            break;
+        }
+    }
+    Ok(TracerouteReport { hops, reached })
+}
+
+/// A UDP datagram from `source_port` to `destination_port`, left without
+/// checksum as allowed over IPv4.
+fn udp_probe(source_port: u16, destination_port: u16, payload: &[u8]) -> Vec<u8> {
+    let length = (UDP_HEADER_LENGTH + payload.len()) as u16;
+    let mut data = Vec::with_capacity(length as usize);
+    data.extend_from_slice(&source_port.to_be_bytes());
+    data.extend_from_slice(&destination_port.to_be_bytes());
+    data.extend_from_slice(&length.to_be_bytes());
+    data.extend_from_slice(&[0, 0]);
+    data.extend_from_slice(payload);
+    data
+}
+
+/// Length of the IP datagram carrying a probe of `size` bytes of data.
+pub fn datagram_length(method: TracerouteMethod, size: usize) -> usize {
+    match method {
+        TracerouteMethod::ICMP => IP_HEADER_LENGTH_MIN + ICMP_HEADER_LENGTH + size,
+        TracerouteMethod::UDP => IP_HEADER_LENGTH_MIN + UDP_HEADER_LENGTH + size,
+    }
+}